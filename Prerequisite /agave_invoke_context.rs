@@ -1,6 +1,9 @@
 use {
     crate::{
-        execution_budget::{SVMTransactionExecutionBudget, SVMTransactionExecutionCost},
+        execution_budget::{
+            SVMTransactionExecutionBudget, SVMTransactionExecutionCost, MAX_COMPUTE_UNIT_LIMIT,
+            MAX_HEAP_FRAME_BYTES, MIN_HEAP_FRAME_BYTES,
+        },
         loaded_programs::{
             ProgramCacheEntry, ProgramCacheEntryType, ProgramCacheForTxBatch,
             ProgramRuntimeEnvironments,
@@ -8,14 +11,15 @@ use {
         stable_log,
         sysvar_cache::SysvarCache,
     },
-    solana_account::{create_account_shared_data_for_test, AccountSharedData},
-    solana_clock::Slot,
+    solana_account::{create_account_shared_data_for_test, AccountSharedData, ReadableAccount},
+    solana_clock::{Clock, Slot},
     solana_epoch_schedule::EpochSchedule,
     solana_hash::Hash,
-    solana_instruction::{error::InstructionError, AccountMeta},
-    solana_log_collector::{ic_msg, LogCollector},
+    solana_instruction::{error::InstructionError, AccountMeta, TRANSACTION_LEVEL_STACK_HEIGHT},
+    solana_log_collector::{ic_logger_msg, ic_msg, LogCollector},
     solana_measure::measure::Measure,
     solana_pubkey::Pubkey,
+    solana_rent::Rent,
     solana_sbpf::{
         ebpf::MM_HEAP_START,
         error::{EbpfError, ProgramResult},
@@ -31,19 +35,104 @@ use {
     solana_svm_feature_set::SVMFeatureSet,
     solana_timings::{ExecuteDetailsTimings, ExecuteTimings},
     solana_transaction_context::{
-        IndexOfAccount, InstructionAccount, TransactionAccount, TransactionContext,
+        IndexOfAccount, InstructionAccount, InstructionContext, TransactionAccount,
+        TransactionContext,
     },
     solana_type_overrides::sync::{atomic::Ordering, Arc},
     std::{
         alloc::Layout,
-        cell::RefCell,
+        cell::{Cell, Ref, RefCell},
+        collections::{BTreeMap, HashMap, HashSet},
         fmt::{self, Debug},
+        io::{self, Read, Write},
+        iter,
         rc::Rc,
+        time::{Duration, Instant},
     },
 };
 
+pub use solana_cpi::MAX_RETURN_DATA;
+
 pub type BuiltinFunctionWithContext = BuiltinFunction<InvokeContext<'static>>;
 
+/// Names of the syscalls a program runtime environment can register, as reported by
+/// `InvokeContext::available_syscalls()`
+pub const SYSCALL_NAMES: &[&str] = &[
+    "abort",
+    "sol_panic_",
+    "sol_log_",
+    "sol_log_64_",
+    "sol_log_pubkey",
+    "sol_log_compute_units_",
+    "sol_log_data",
+    "sol_create_program_address",
+    "sol_try_find_program_address",
+    "sol_sha256",
+    "sol_keccak256",
+    "sol_secp256k1_recover",
+    "sol_blake3",
+    "sol_curve_validate_point",
+    "sol_curve_group_op",
+    "sol_curve_multiscalar_mul",
+    "sol_get_clock_sysvar",
+    "sol_get_epoch_schedule_sysvar",
+    "sol_get_fees_sysvar",
+    "sol_get_rent_sysvar",
+    "sol_get_last_restart_slot",
+    "sol_get_epoch_rewards_sysvar",
+    "sol_get_sysvar",
+    "sol_get_epoch_stake",
+    "sol_memcpy_",
+    "sol_memmove_",
+    "sol_memset_",
+    "sol_memcmp_",
+    "sol_get_processed_sibling_instruction",
+    "sol_get_stack_height",
+    "sol_set_return_data",
+    "sol_get_return_data",
+    "sol_invoke_signed_c",
+    "sol_invoke_signed_rust",
+    "sol_alloc_free_",
+    "sol_alt_bn128_group_op",
+    "sol_alt_bn128_compression",
+    "sol_big_mod_exp",
+    "sol_poseidon",
+    "sol_remaining_compute_units",
+];
+
+/// Compute units charged by syscalls and their invocation counts, keyed by syscall name
+///
+/// Kept by the `InvokeContext` next to its `ExecuteDetailsTimings`, which has no room for it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SyscallCosts {
+    /// Compute units charged by each syscall
+    pub compute_units: BTreeMap<&'static str, u64>,
+    /// Number of invocations of each syscall
+    pub counts: BTreeMap<&'static str, u64>,
+}
+
+impl SyscallCosts {
+    /// Record an invocation of the syscall `name` which charged `cu`
+    pub fn record(&mut self, name: &'static str, cu: u64) {
+        let total = self.compute_units.entry(name).or_default();
+        *total = total.saturating_add(cu);
+        let count = self.counts.entry(name).or_default();
+        *count = count.saturating_add(1);
+    }
+
+    /// Add the totals of `other`, e.g. to aggregate the transactions of a batch
+    pub fn accumulate(&mut self, other: &SyscallCosts) {
+        for (name, cu) in other.compute_units.iter() {
+            let total = self.compute_units.entry(name).or_default();
+            *total = total.saturating_add(*cu);
+        }
+        for (name, count) in other.counts.iter() {
+            let total = self.counts.entry(name).or_default();
+            *total = total.saturating_add(*count);
+        }
+    }
+}
+
 /// Adapter so we can unify the interfaces of built-in programs and syscalls
 #[macro_export]
 macro_rules! declare_process_instruction {
@@ -84,24 +173,56 @@ macro_rules! declare_process_instruction {
 
 impl ContextObject for InvokeContext<'_> {
     fn trace(&mut self, state: [u64; 12]) {
-        self.syscall_context
+        if !self.tracing_enabled {
+            return;
+        }
+        let remaining_trace_rows = self.remaining_trace_rows();
+        let trace_log = &mut self
+            .syscall_context
             .last_mut()
             .unwrap()
             .as_mut()
             .unwrap()
-            .trace_log
-            .push(state);
+            .trace_log;
+        if remaining_trace_rows.is_some_and(|remaining| trace_log.len() >= remaining) {
+            self.traces_truncated = true;
+            return;
+        }
+        trace_log.push(state);
     }
 
     fn consume(&mut self, amount: u64) {
+        debug_assert!(
+            self.compute_meter_invariant_ok(),
+            "compute meter exceeds its ceiling"
+        );
         // 1 to 1 instruction to compute unit mapping
         // ignore overflow, Ebpf will bail if exceeded
-        let mut compute_meter = self.compute_meter.borrow_mut();
-        *compute_meter = compute_meter.saturating_sub(amount);
+        let remaining = {
+            let mut compute_meter = self.compute_meter.borrow_mut();
+            *compute_meter = compute_meter.saturating_sub(amount);
+            *compute_meter
+        };
+        if !self.is_consumption_monitored() {
+            return;
+        }
+        if let Some(consume_hook) = self.consume_hook.get_mut() {
+            consume_hook(amount, remaining);
+        }
+        self.warn_on_soft_limit();
+        self.poll_execution_interrupt();
     }
 
     fn get_remaining(&self) -> u64 {
-        *self.compute_meter.borrow()
+        let remaining = *self.compute_meter.borrow();
+        if !self.can_interrupt_execution() {
+            return remaining;
+        }
+        // Reporting no compute units left makes the VM stop at its next meter check
+        if self.check_execution_interrupt().is_some() {
+            return 0;
+        }
+        remaining
     }
 }
 
@@ -113,14 +234,39 @@ impl fmt::Display for AllocErr {
     }
 }
 
+/// Heap usage of a [BpfAllocator]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AllocatorStats {
+    /// Bytes handed out so far, including alignment padding
+    pub bytes_allocated: u64,
+    /// Highest heap usage, the same as `bytes_allocated` as nothing is ever freed
+    pub peak_usage: u64,
+    /// Number of successful allocations
+    pub allocation_count: u64,
+}
+
+#[derive(Clone)]
 pub struct BpfAllocator {
     len: u64,
     pos: u64,
+    allocation_count: u64,
 }
 
 impl BpfAllocator {
     pub fn new(len: u64) -> Self {
-        Self { len, pos: 0 }
+        Self {
+            len,
+            pos: 0,
+            allocation_count: 0,
+        }
+    }
+
+    pub fn stats(&self) -> AllocatorStats {
+        AllocatorStats {
+            bytes_allocated: self.pos,
+            peak_usage: self.pos,
+            allocation_count: self.allocation_count,
+        }
     }
 
     pub fn alloc(&mut self, layout: Layout) -> Result<u64, AllocErr> {
@@ -134,6 +280,7 @@ impl BpfAllocator {
             self.pos = self.pos.saturating_add(bytes_to_align);
             let addr = MM_HEAP_START.saturating_add(self.pos);
             self.pos = self.pos.saturating_add(layout.size() as u64);
+            self.allocation_count = self.allocation_count.saturating_add(1);
             Ok(addr)
         } else {
             Err(AllocErr)
@@ -141,9 +288,14 @@ impl BpfAllocator {
     }
 }
 
+#[derive(Clone)]
 pub struct EnvironmentConfig<'a> {
     pub blockhash: Hash,
     pub blockhash_lamports_per_signature: u64,
+    /// Wall clock limit of the execution of a program, including the CPIs it makes.
+    /// Checked whenever compute units are consumed, so a running program is stopped at its
+    /// next meter check, compute units remain the primary bound.
+    pub execution_timeout: Option<Duration>,
     epoch_stake_callback: &'a dyn InvokeContextCallback,
     feature_set: &'a SVMFeatureSet,
     sysvar_cache: &'a SysvarCache,
@@ -159,13 +311,463 @@ impl<'a> EnvironmentConfig<'a> {
         Self {
             blockhash,
             blockhash_lamports_per_signature,
+            execution_timeout: None,
             epoch_stake_callback,
             feature_set,
             sysvar_cache,
         }
     }
+
+    /// Feature set of the invocation environment, to check which features are active
+    pub fn active_features(&self) -> &'a SVMFeatureSet {
+        self.feature_set
+    }
+}
+
+/// One decoded row of a trace
+///
+/// A raw row holds the registers r0 to r10 at the indices 0 to 10
+/// and the program counter at index 11.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TraceFrame {
+    pub r0: u64,
+    pub r1: u64,
+    pub r2: u64,
+    pub r3: u64,
+    pub r4: u64,
+    pub r5: u64,
+    pub r6: u64,
+    pub r7: u64,
+    pub r8: u64,
+    pub r9: u64,
+    /// Frame pointer
+    pub r10: u64,
+    /// Program counter, in instructions
+    pub pc: u64,
+}
+impl From<[u64; 12]> for TraceFrame {
+    fn from(row: [u64; 12]) -> Self {
+        let [r0, r1, r2, r3, r4, r5, r6, r7, r8, r9, r10, pc] = row;
+        Self {
+            r0,
+            r1,
+            r2,
+            r3,
+            r4,
+            r5,
+            r6,
+            r7,
+            r8,
+            r9,
+            r10,
+            pc,
+        }
+    }
+}
+
+/// Prices the operations `InvokeContext` charges compute units for
+///
+/// Implemented by [SVMTransactionExecutionCost] with the costs used on chain,
+/// which is what an `InvokeContext` uses unless set otherwise.
+pub trait CostModel {
+    /// Cost of logging a message of `len` bytes
+    fn log_cost(&self, len: usize) -> u64;
+    /// Base cost of invoking the syscall `name`
+    fn syscall_cost(&self, name: &str) -> u64;
+    /// Cost of reading a sysvar of `size` bytes
+    fn sysvar_cost(&self, size: usize) -> u64;
+    /// Cost of setting `len` bytes of return data
+    fn return_data_cost(&self, len: usize) -> u64;
+    /// Cost of a heap of `heap_bytes`, which does not exceed `MAX_HEAP_FRAME_BYTES`
+    fn heap_cost(&self, heap_bytes: u32) -> u64;
+    /// Cost of a memory operation, e.g. `sol_memcpy_`, over `bytes` bytes
+    fn mem_op_cost(&self, bytes: u64) -> u64;
+}
+impl CostModel for SVMTransactionExecutionCost {
+    fn log_cost(&self, len: usize) -> u64 {
+        self.syscall_base_cost.max(len as u64)
+    }
+
+    fn syscall_cost(&self, _name: &str) -> u64 {
+        self.syscall_base_cost
+    }
+
+    fn sysvar_cost(&self, size: usize) -> u64 {
+        self.sysvar_base_cost.saturating_add(size as u64)
+    }
+
+    fn return_data_cost(&self, len: usize) -> u64 {
+        (len as u64)
+            .checked_div(self.cpi_bytes_per_unit)
+            .unwrap_or(u64::MAX)
+            .saturating_add(self.syscall_base_cost)
+    }
+
+    fn heap_cost(&self, heap_bytes: u32) -> u64 {
+        let pages = heap_bytes.div_ceil(MIN_HEAP_FRAME_BYTES).saturating_sub(1);
+        u64::from(pages).saturating_mul(self.heap_cost)
+    }
+
+    fn mem_op_cost(&self, bytes: u64) -> u64 {
+        self.mem_op_base_cost.max(
+            bytes
+                .checked_div(self.cpi_bytes_per_unit)
+                .unwrap_or(u64::MAX),
+        )
+    }
+}
+
+/// First divergence between the traces of an execution and a recorded reference,
+/// as found by `InvokeContext::verify_trace()`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceMismatch {
+    /// The number of frames differs
+    FrameCount { expected: usize, actual: usize },
+    /// The number of rows of `frame` differs
+    RowCount {
+        frame: usize,
+        expected: usize,
+        actual: usize,
+    },
+    /// Register `register` of `row` of `frame` differs, the pc being register 11
+    Register {
+        frame: usize,
+        row: usize,
+        register: usize,
+        expected: u64,
+        actual: u64,
+    },
+}
+impl fmt::Display for TraceMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::FrameCount { expected, actual } => {
+                write!(f, "Expected {expected} frames but found {actual}")
+            }
+            Self::RowCount {
+                frame,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Expected {expected} rows in frame {frame} but found {actual}"
+            ),
+            Self::Register {
+                frame,
+                row,
+                register,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Expected {expected:#x} in register {register} of row {row} in frame {frame} \
+                 but found {actual:#x}"
+            ),
+        }
+    }
+}
+
+/// Receives trace rows as frames are popped, e.g. to stream them to disk
+pub trait TraceSink {
+    /// Record one row of the trace of the frame at index `frame`
+    fn record(&mut self, frame: usize, row: [u64; 12]);
+}
+
+/// Executes programs in place of the built-in VM, e.g. an instrumented interpreter
+pub trait VmBackend {
+    /// Execute `entry` as the program of the current frame of `invoke_context`,
+    /// consuming compute units from its meter like the built-in VM does
+    fn execute(
+        &self,
+        entry: &ProgramCacheEntry,
+        invoke_context: &mut InvokeContext,
+    ) -> Result<(), InstructionError>;
+}
+
+/// Size of a record written by [WriterTraceSink], the frame index followed by the row
+const TRACE_RECORD_SIZE: usize = 13 * 8;
+
+/// Writes trace rows to a writer, as little-endian `u64`s prefixed with the frame index
+///
+/// See [CompressedFileTraceSink] to compress the rows. Use [read_trace_rows] to decode them.
+pub struct WriterTraceSink<W: Write> {
+    writer: W,
+    flush_interval: usize,
+    unflushed_rows: usize,
+    error: Option<io::Error>,
+}
+impl<W: Write> WriterTraceSink<W> {
+    /// Flush the writer after every `flush_interval` rows, or only on `finish()` if zero
+    pub fn new(writer: W, flush_interval: usize) -> Self {
+        Self {
+            writer,
+            flush_interval,
+            unflushed_rows: 0,
+            error: None,
+        }
+    }
+
+    /// Flush and return the writer, or the first error encountered while recording
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn write_row(&mut self, frame: usize, row: [u64; 12]) -> io::Result<()> {
+        let mut record = [0; TRACE_RECORD_SIZE];
+        for (bytes, value) in record
+            .chunks_exact_mut(8)
+            .zip(iter::once(frame as u64).chain(row))
+        {
+            bytes.copy_from_slice(&value.to_le_bytes());
+        }
+        self.writer.write_all(&record)?;
+        self.unflushed_rows = self.unflushed_rows.saturating_add(1);
+        if self.flush_interval != 0 && self.unflushed_rows >= self.flush_interval {
+            self.unflushed_rows = 0;
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+}
+impl<W: Write> TraceSink for WriterTraceSink<W> {
+    fn record(&mut self, frame: usize, row: [u64; 12]) {
+        // Stop at the first error, it is reported by finish()
+        if self.error.is_none() {
+            self.error = self.write_row(frame, row).err();
+        }
+    }
+}
+
+/// Writes trace rows like [WriterTraceSink], compressed with zstd
+///
+/// Use [read_compressed_trace_rows] to decode the rows.
+pub struct CompressedFileTraceSink<W: Write> {
+    trace_sink: WriterTraceSink<zstd::Encoder<'static, W>>,
+}
+impl<W: Write> CompressedFileTraceSink<W> {
+    /// Compress at the zstd `level`, 0 selecting zstd's default, flushing the compressed
+    /// stream after every `flush_interval` rows, or only on `finish()` if zero
+    pub fn new(writer: W, level: i32, flush_interval: usize) -> io::Result<Self> {
+        Ok(Self {
+            trace_sink: WriterTraceSink::new(zstd::Encoder::new(writer, level)?, flush_interval),
+        })
+    }
+
+    /// Complete the compressed stream and return the writer, or the first error encountered
+    /// while recording
+    pub fn finish(self) -> io::Result<W> {
+        self.trace_sink.finish()?.finish()
+    }
+}
+impl<W: Write> TraceSink for CompressedFileTraceSink<W> {
+    fn record(&mut self, frame: usize, row: [u64; 12]) {
+        self.trace_sink.record(frame, row);
+    }
+}
+
+/// Decode the `(frame, row)` pairs written by a [WriterTraceSink], one record at a time
+pub fn read_trace_rows<R: Read>(reader: R) -> TraceRows<R> {
+    TraceRows { reader }
+}
+
+/// Decode the `(frame, row)` pairs written by a [CompressedFileTraceSink], one record at a time
+pub fn read_compressed_trace_rows<R: Read>(
+    reader: R,
+) -> io::Result<TraceRows<zstd::Decoder<'static, io::BufReader<R>>>> {
+    zstd::Decoder::new(reader).map(read_trace_rows)
+}
+
+/// Iterator over the `(frame, row)` pairs of a trace, see [read_trace_rows]
+///
+/// Yields an `io::ErrorKind::InvalidData` error if the trace ends within a record.
+pub struct TraceRows<R: Read> {
+    reader: R,
+}
+impl<R: Read> TraceRows<R> {
+    /// Fill `record`, returning how many bytes were read before the end of the trace
+    fn read_record(&mut self, record: &mut [u8; TRACE_RECORD_SIZE]) -> io::Result<usize> {
+        let mut filled = 0;
+        while filled < TRACE_RECORD_SIZE {
+            match self.reader.read(&mut record[filled..]) {
+                Ok(0) => break,
+                Ok(read) => filled = filled.saturating_add(read),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(filled)
+    }
+}
+impl<R: Read> Iterator for TraceRows<R> {
+    type Item = io::Result<(usize, [u64; 12])>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut record = [0; TRACE_RECORD_SIZE];
+        match self.read_record(&mut record) {
+            Ok(0) => return None,
+            Ok(TRACE_RECORD_SIZE) => {}
+            Ok(_) => {
+                return Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "truncated trace record",
+                )))
+            }
+            Err(err) => return Some(Err(err)),
+        }
+        let mut values = record
+            .chunks_exact(8)
+            .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()));
+        let frame = match usize::try_from(values.next().unwrap()) {
+            Ok(frame) => frame,
+            Err(err) => return Some(Err(io::Error::new(io::ErrorKind::InvalidData, err))),
+        };
+        let mut row = [0; 12];
+        for (cell, value) in row.iter_mut().zip(values) {
+            *cell = value;
+        }
+        Some(Ok((frame, row)))
+    }
+}
+
+/// Instruction accounts and program indices of an instruction validated by
+/// `InvokeContext::prepare_instructions()`, as consumed by `InvokeContext::process_instruction()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreparedInstruction {
+    pub instruction_accounts: Vec<InstructionAccount>,
+    pub program_indices: Vec<IndexOfAccount>,
+}
+
+/// An `InstructionError` of `InvokeContext::process_instruction_detailed()`,
+/// together with where it happened and what it cost
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DetailedInstructionError {
+    pub error: InstructionError,
+    /// Program of the failed instruction, `Pubkey::default()` if there are no program indices
+    pub program_id: Pubkey,
+    /// Compute units consumed by the failed instruction, including its CPIs
+    pub compute_consumed: u64,
+    /// Stack height the failed instruction was (or would have been) executed at
+    pub stack_height: usize,
+}
+impl fmt::Display for DetailedInstructionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} (program {} at stack height {}, {} compute units consumed)",
+            self.error, self.program_id, self.stack_height, self.compute_consumed,
+        )
+    }
+}
+
+/// State of an operation registered via `InvokeContext::register_promise()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PromiseState {
+    Pending,
+    Resolved(Vec<u8>),
+    Failed(InstructionError),
+}
+
+/// Seeded pseudo random number generator (SplitMix64) for syscalls in tests
+///
+/// Yields the same sequence for the same seed, so that fuzzers can reproduce runs. Only
+/// available with the `dev-context-only-utils` feature and not cryptographically secure,
+/// so it must never be used for consensus relevant logic.
+#[cfg(feature = "dev-context-only-utils")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeterministicRng {
+    state: u64,
+}
+#[cfg(feature = "dev-context-only-utils")]
+impl DeterministicRng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    pub fn fill_bytes(&mut self, bytes: &mut [u8]) {
+        for chunk in bytes.chunks_mut(8) {
+            let random = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&random[..chunk.len()]);
+        }
+    }
+}
+
+/// Restores the compute units withheld by `InvokeContext::with_reduced_budget()` when dropped
+#[must_use = "the reduced budget is lifted as soon as the guard is dropped"]
+pub struct ComputeBudgetGuard {
+    compute_meter: Rc<RefCell<u64>>,
+    compute_meter_ceiling: Rc<Cell<u64>>,
+    compute_meter_withheld: Rc<Cell<u64>>,
+    withheld: u64,
+}
+impl Drop for ComputeBudgetGuard {
+    fn drop(&mut self) {
+        let compute_meter_withheld = self.compute_meter_withheld.get();
+        self.compute_meter_withheld
+            .set(compute_meter_withheld.saturating_sub(self.withheld));
+        // The meter may have been refilled by `InvokeContext::reset()` in the meantime
+        let mut compute_meter = self.compute_meter.borrow_mut();
+        *compute_meter = compute_meter
+            .saturating_add(self.withheld)
+            .min(self.compute_meter_ceiling.get());
+    }
+}
+
+/// Compute meter which several contexts draw from, see `InvokeContext::new_with_shared_meter()`
+///
+/// Clones share the remaining compute units as well as their upper bound, so that raising
+/// the meter in one context, e.g. via `InvokeContext::apply_priority_boost()`, raises the
+/// bound for all contexts drawing from it.
+#[derive(Clone, Debug)]
+pub struct SharedComputeMeter {
+    remaining: Rc<RefCell<u64>>,
+    ceiling: Rc<Cell<u64>>,
+}
+impl SharedComputeMeter {
+    pub fn new(remaining: u64) -> Self {
+        Self {
+            remaining: Rc::new(RefCell::new(remaining)),
+            ceiling: Rc::new(Cell::new(remaining)),
+        }
+    }
+
+    /// Compute units remaining in the meter
+    pub fn remaining(&self) -> u64 {
+        *self.remaining.borrow()
+    }
+}
+
+/// Reason for which the context stopped the execution of an instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExecutionInterrupt {
+    /// A frame consumed more than the cap set via `InvokeContext::set_frame_cu_cap()`,
+    /// reported as `InstructionError::ComputationalBudgetExceeded`
+    FrameCuCapExceeded,
+    /// The execution exceeded `EnvironmentConfig::execution_timeout`,
+    /// reported as `InstructionError::ProgramFailedToComplete`
+    Timeout,
+}
+impl From<ExecutionInterrupt> for InstructionError {
+    fn from(execution_interrupt: ExecutionInterrupt) -> Self {
+        match execution_interrupt {
+            ExecutionInterrupt::FrameCuCapExceeded => Self::ComputationalBudgetExceeded,
+            ExecutionInterrupt::Timeout => Self::ProgramFailedToComplete,
+        }
+    }
 }
 
+#[derive(Clone)]
 pub struct SyscallContext {
     pub allocator: BpfAllocator,
     pub accounts_metadata: Vec<SerializedAccountMetadata>,
@@ -181,6 +783,49 @@ pub struct SerializedAccountMetadata {
     pub vm_owner_addr: u64,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AccountPrivilege {
+    Signer,
+    Writable,
+}
+
+/// Diagnostic for an account privilege requested by a callee but not held by its caller
+struct PrivilegeEscalation<'b> {
+    pubkey: &'b Pubkey,
+    instruction_account: &'b InstructionAccount,
+    privilege: AccountPrivilege,
+    /// Whether the caller holds the privilege
+    caller: bool,
+}
+
+impl fmt::Display for PrivilegeEscalation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (flag, callee) = match self.privilege {
+            AccountPrivilege::Signer => ("is_signer", self.instruction_account.is_signer),
+            AccountPrivilege::Writable => ("is_writable", self.instruction_account.is_writable),
+        };
+        write!(
+            f,
+            "Privilege escalation of account {} (index {} in caller, {} in callee): \
+             {} is {} in caller but {} in callee",
+            self.pubkey,
+            self.instruction_account.index_in_caller,
+            self.instruction_account.index_in_callee,
+            flag,
+            self.caller,
+            callee,
+        )
+    }
+}
+
+/// Whether `owner_id` is one of the loaders of programs executed in the VM
+fn is_bpf_loader(owner_id: &Pubkey) -> bool {
+    bpf_loader_deprecated::check_id(owner_id)
+        || bpf_loader::check_id(owner_id)
+        || bpf_loader_upgradeable::check_id(owner_id)
+        || loader_v4::check_id(owner_id)
+}
+
 /// Main pipeline from runtime to program execution.
 pub struct InvokeContext<'a> {
     /// Information about the currently executing transaction.
@@ -193,18 +838,109 @@ pub struct InvokeContext<'a> {
     compute_budget: SVMTransactionExecutionBudget,
     /// The compute cost for the current invocation.
     execution_cost: SVMTransactionExecutionCost,
+    /// Prices the operations charged by the helpers of the context
+    cost_model: Arc<dyn CostModel>,
     /// Instruction compute meter, for tracking compute units consumed against
     /// the designated compute budget during program execution.
-    compute_meter: RefCell<u64>,
+    /// Shared with other contexts if created via `new_with_shared_meter()`.
+    compute_meter: Rc<RefCell<u64>>,
+    /// Upper bound of the compute meter, which only consumption may lower.
+    /// Shared along with the compute meter.
+    compute_meter_ceiling: Rc<Cell<u64>>,
+    /// Compute units withheld from the meter by the outstanding `ComputeBudgetGuard`s
+    compute_meter_withheld: Rc<Cell<u64>>,
+    /// Whether the compute meter was supplied via `new_with_shared_meter()`
+    compute_meter_shared: bool,
+    /// Whether `set_compute_unit_limit()` may exceed the compute unit limit of the budget
+    allow_budget_increase: bool,
+    /// Remaining compute units which `apply_priority_boost()` never exceeds
+    priority_boost_cap: u64,
     log_collector: Option<Rc<RefCell<LogCollector>>>,
     /// Latest measurement not yet accumulated in [ExecuteDetailsTimings::execute_us]
     pub execute_time: Option<Measure>,
+    /// Whether the execution time of builtin programs is measured
+    measure_execute_time: bool,
     pub timings: ExecuteDetailsTimings,
     pub syscall_context: Vec<Option<SyscallContext>>,
     traces: Vec<Vec<[u64; 12]>>,
+    /// Number of trace rows recorded for each frame in `traces`
+    trace_frame_lengths: Vec<usize>,
+    /// Number of trace rows kept in `traces`, which `max_trace_rows` bounds
+    recorded_trace_rows: usize,
+    /// Maximum number of trace rows kept in `traces`, if set
+    max_trace_rows: Option<usize>,
+    /// Whether trace rows were dropped because of `max_trace_rows`
+    traces_truncated: bool,
+    /// Compute units charged by each syscall and its number of invocations
+    syscall_costs: RefCell<SyscallCosts>,
+    /// Compute units charged via `consume_with_label()`, keyed by label
+    compute_breakdown: RefCell<BTreeMap<&'static str, u64>>,
+    /// Maximum height of the invocation stack enforced by `push()`
+    max_invoke_stack_height: usize,
+    /// Programs which `push()` allows to be reentered while already on the stack.
+    /// The stack height limit still applies to them, which bounds recursion.
+    reentrancy_allowlist: HashSet<Pubkey>,
+    /// Only keep the traces of this program, if set
+    trace_filter: Option<Pubkey>,
+    /// Whether traces are recorded at all
+    tracing_enabled: bool,
+    /// Receives the trace rows instead of `traces`, if set
+    trace_sink: Option<Box<dyn TraceSink>>,
+    /// Executes programs instead of the built-in VM, if set
+    vm_backend: Option<Rc<dyn VmBackend>>,
+    /// Fraction of the compute unit limit at which a warning is logged, if set
+    soft_limit_fraction: Option<f64>,
+    /// Whether the soft limit warning was already logged in the current frame
+    soft_limit_warned: Cell<bool>,
+    /// Unspent compute units at the time each frame on the stack was pushed,
+    /// see `get_unspent_compute_units()`
+    frame_entry_compute_meter: Vec<u64>,
+    /// Compute units consumed by the already popped nested frames of each frame on the stack
+    frame_nested_compute_units: Vec<u64>,
+    /// Most compute units consumed by a single popped frame, excluding its nested frames
+    peak_frame_consumption: u64,
+    /// Unspent compute units when recording the consumption timeline was enabled, if it is
+    consumption_timeline_start: Option<u64>,
+    /// Stack height and compute units consumed since `consumption_timeline_start`,
+    /// sampled after every push and pop
+    consumption_timeline: Vec<(usize, u64)>,
+    /// Compute units a single frame may consume, excluding its nested frames, if set
+    frame_cu_cap: Option<u64>,
+    /// Why an instruction was stopped since the last top level frame was pushed, if it was
+    execution_interrupt: Cell<Option<ExecutionInterrupt>>,
+    /// Instant by which the executing programs have to finish, if limited
+    execution_deadline: Option<Instant>,
+    /// Clock the execution timeout is measured with
+    time_source: Rc<dyn Fn() -> Instant>,
+    /// Compute units each frame on the stack reserved via `reserve_compute_units()`
+    compute_reserves: RefCell<Vec<u64>>,
+    /// Whether the accounts of pushed frames are recorded
+    track_accounts_touched: bool,
+    /// Indices in the transaction of the instruction accounts of all pushed frames
+    accounts_touched: Vec<IndexOfAccount>,
+    /// The subset of `accounts_touched` which were writable in at least one frame
+    writable_accounts_touched: Vec<IndexOfAccount>,
+    /// Source of reproducible randomness for syscalls in tests
+    #[cfg(feature = "dev-context-only-utils")]
+    rng: DeterministicRng,
+    /// Called with the amount and the remaining compute units on every consumption
+    consume_hook: RefCell<Option<Box<dyn FnMut(u64, u64)>>>,
+    /// Called with the new stack height whenever a frame is pushed
+    on_push: Option<Box<dyn FnMut(usize)>>,
+    /// Called with the new stack height whenever a frame is popped
+    on_pop: Option<Box<dyn FnMut(usize)>>,
+    /// Operations completed outside of the context, keyed by promise id
+    pending_promises: HashMap<u64, PromiseState>,
+    /// Id of the next promise registered via `register_promise()`
+    next_promise_id: u64,
 }
 
 impl<'a> InvokeContext<'a> {
+    /// Create a context whose compute meter starts at the compute unit limit of `compute_budget`
+    ///
+    /// A compute unit limit of zero is valid. The first charge then fails with
+    /// `InstructionError::ComputationalBudgetExceeded`, which for a builtin is the charge of
+    /// its cost before any of its code runs, and logging fails the same way.
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         transaction_context: &'a mut TransactionContext,
@@ -214,19 +950,200 @@ impl<'a> InvokeContext<'a> {
         compute_budget: SVMTransactionExecutionBudget,
         execution_cost: SVMTransactionExecutionCost,
     ) -> Self {
-        Self {
+        InvokeContextBuilder::default()
+            .transaction_context(transaction_context)
+            .program_cache(program_cache_for_tx_batch)
+            .environment_config(environment_config)
+            .log_collector(log_collector)
+            .compute_budget(compute_budget)
+            .execution_cost(execution_cost)
+            .build()
+            .expect("all required fields are set")
+    }
+
+    /// Like `new()` but draws compute units from the given meter instead of a meter
+    /// of its own, so that several contexts can share one budget
+    ///
+    /// The meter is not reset to the compute unit limit of `compute_budget`. `Rc` and
+    /// `RefCell` make the sharing single threaded, all contexts drawing from the meter
+    /// must live on the same thread. `reset()` leaves the shared meter alone, as the other
+    /// contexts may still be drawing from it.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_with_shared_meter(
+        transaction_context: &'a mut TransactionContext,
+        program_cache_for_tx_batch: &'a mut ProgramCacheForTxBatch,
+        environment_config: EnvironmentConfig<'a>,
+        log_collector: Option<Rc<RefCell<LogCollector>>>,
+        compute_budget: SVMTransactionExecutionBudget,
+        execution_cost: SVMTransactionExecutionCost,
+        compute_meter: SharedComputeMeter,
+    ) -> Self {
+        InvokeContextBuilder::default()
+            .transaction_context(transaction_context)
+            .program_cache(program_cache_for_tx_batch)
+            .environment_config(environment_config)
+            .log_collector(log_collector)
+            .compute_budget(compute_budget)
+            .execution_cost(execution_cost)
+            .shared_compute_meter(compute_meter)
+            .build()
+            .expect("all required fields are set")
+    }
+
+    /// Like `new()` but with the per frame buffers pre-sized for `expected_depth` nested
+    /// frames and the trace buffers for `expected_traced_frames` traced frames
+    ///
+    /// Each frame popped with a syscall context adds one trace, so for trace heavy
+    /// transactions this avoids reallocating while the invocation stack grows.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_with_capacity(
+        transaction_context: &'a mut TransactionContext,
+        program_cache_for_tx_batch: &'a mut ProgramCacheForTxBatch,
+        environment_config: EnvironmentConfig<'a>,
+        log_collector: Option<Rc<RefCell<LogCollector>>>,
+        compute_budget: SVMTransactionExecutionBudget,
+        execution_cost: SVMTransactionExecutionCost,
+        expected_depth: usize,
+        expected_traced_frames: usize,
+    ) -> Self {
+        let mut invoke_context = Self::new(
             transaction_context,
             program_cache_for_tx_batch,
             environment_config,
             log_collector,
             compute_budget,
             execution_cost,
-            compute_meter: RefCell::new(compute_budget.compute_unit_limit),
-            execute_time: None,
-            timings: ExecuteDetailsTimings::default(),
-            syscall_context: Vec::new(),
-            traces: Vec::new(),
+        );
+        invoke_context.syscall_context.reserve(expected_depth);
+        invoke_context
+            .frame_entry_compute_meter
+            .reserve(expected_depth);
+        invoke_context
+            .frame_nested_compute_units
+            .reserve(expected_depth);
+        invoke_context
+            .compute_reserves
+            .get_mut()
+            .reserve(expected_depth);
+        invoke_context.traces.reserve(expected_traced_frames);
+        invoke_context
+            .trace_frame_lengths
+            .reserve(expected_traced_frames);
+        invoke_context
+    }
+
+    /// Reset the per invocation state, so that the context can be reused
+    ///
+    /// Clears the syscall contexts, traces and timings while keeping the capacity of their
+    /// buffers, and refills the compute meter to the compute unit limit unless the meter is
+    /// shared with other contexts. Promises of the previous transaction are dropped, but
+    /// promise ids keep counting up, so that a late settlement of an old promise can not hit
+    /// a new one. Outstanding `ComputeBudgetGuard`s no longer withhold compute units.
+    /// Configuration set on the context is kept. The `transaction_context` and
+    /// `program_cache_for_tx_batch` are not touched, so the borrows the context was created
+    /// with must remain valid.
+    pub fn reset(&mut self) {
+        if !self.compute_meter_shared {
+            *self.compute_meter.borrow_mut() = self.compute_budget.compute_unit_limit;
+        }
+        // Outstanding guards give back their compute units clamped to the ceiling of the meter
+        self.compute_meter_withheld = Rc::new(Cell::new(0));
+        self.execute_time = None;
+        self.timings = ExecuteDetailsTimings::default();
+        self.soft_limit_warned.set(false);
+        self.pending_promises.clear();
+        self.syscall_context.clear();
+        self.frame_entry_compute_meter.clear();
+        self.frame_nested_compute_units.clear();
+        self.execution_interrupt.set(None);
+        self.execution_deadline = None;
+        self.peak_frame_consumption = 0;
+        self.compute_reserves.borrow_mut().clear();
+        self.consumption_timeline.clear();
+        if self.consumption_timeline_start.is_some() {
+            self.consumption_timeline_start = Some(self.get_unspent_compute_units());
+        }
+        self.accounts_touched.clear();
+        self.writable_accounts_touched.clear();
+        self.traces.clear();
+        self.trace_frame_lengths.clear();
+        self.recorded_trace_rows = 0;
+        self.traces_truncated = false;
+        *self.syscall_costs.get_mut() = SyscallCosts::default();
+        self.compute_breakdown.borrow_mut().clear();
+    }
+
+    /// Copy the state of this context into a new one, e.g. to simulate alternatives
+    ///
+    /// Copied: the remaining compute units into a meter of its own, the timings, traces,
+    /// syscall contexts, per frame and per syscall accounting, promises and all configuration.
+    /// Reset: the execution time measurement, the trace sink and the hooks, which can not be
+    /// copied, and the log collector, which is replaced by an empty one if there was one.
+    /// The borrowed `transaction_context` and `program_cache_for_tx_batch` must be supplied
+    /// by the caller, at the same stack height for the syscall contexts to line up.
+    pub fn clone_for_simulation<'b>(
+        &self,
+        transaction_context: &'b mut TransactionContext,
+        program_cache_for_tx_batch: &'b mut ProgramCacheForTxBatch,
+    ) -> InvokeContext<'b>
+    where
+        'a: 'b,
+    {
+        let mut simulation = InvokeContextBuilder::default()
+            .transaction_context(transaction_context)
+            .program_cache(program_cache_for_tx_batch)
+            .environment_config(self.environment_config.clone())
+            .log_collector(self.log_collector.as_ref().map(|_| LogCollector::new_ref()))
+            .compute_budget(self.compute_budget)
+            .execution_cost(self.execution_cost)
+            .cost_model(Arc::clone(&self.cost_model))
+            .max_invoke_stack_height(self.max_invoke_stack_height)
+            .build()
+            .expect("all required fields are set");
+        *simulation.compute_meter.borrow_mut() = self.get_remaining_compute_units();
+        simulation
+            .compute_meter_ceiling
+            .set(self.compute_meter_ceiling.get());
+        simulation
+            .compute_meter_withheld
+            .set(self.compute_meter_withheld.get());
+        simulation.allow_budget_increase = self.allow_budget_increase;
+        simulation.priority_boost_cap = self.priority_boost_cap;
+        simulation.measure_execute_time = self.measure_execute_time;
+        simulation.timings.accumulate(&self.timings);
+        simulation.syscall_context = self.syscall_context.clone();
+        simulation.traces = self.traces.clone();
+        simulation.trace_frame_lengths = self.trace_frame_lengths.clone();
+        simulation.recorded_trace_rows = self.recorded_trace_rows;
+        simulation.max_trace_rows = self.max_trace_rows;
+        simulation.traces_truncated = self.traces_truncated;
+        simulation.syscall_costs = self.syscall_costs.clone();
+        simulation.compute_breakdown = self.compute_breakdown.clone();
+        simulation.reentrancy_allowlist = self.reentrancy_allowlist.clone();
+        simulation.trace_filter = self.trace_filter;
+        simulation.tracing_enabled = self.tracing_enabled;
+        simulation.soft_limit_fraction = self.soft_limit_fraction;
+        simulation.soft_limit_warned = self.soft_limit_warned.clone();
+        simulation.frame_entry_compute_meter = self.frame_entry_compute_meter.clone();
+        simulation.frame_nested_compute_units = self.frame_nested_compute_units.clone();
+        simulation.peak_frame_consumption = self.peak_frame_consumption;
+        simulation.consumption_timeline_start = self.consumption_timeline_start;
+        simulation.consumption_timeline = self.consumption_timeline.clone();
+        simulation.frame_cu_cap = self.frame_cu_cap;
+        simulation.execution_interrupt = self.execution_interrupt.clone();
+        simulation.execution_deadline = self.execution_deadline;
+        simulation.time_source = Rc::clone(&self.time_source);
+        simulation.compute_reserves = self.compute_reserves.clone();
+        simulation.track_accounts_touched = self.track_accounts_touched;
+        simulation.accounts_touched = self.accounts_touched.clone();
+        simulation.writable_accounts_touched = self.writable_accounts_touched.clone();
+        #[cfg(feature = "dev-context-only-utils")]
+        {
+            simulation.rng = self.rng.clone();
         }
+        simulation.pending_promises = self.pending_promises.clone();
+        simulation.next_promise_id = self.next_promise_id;
+        simulation
     }
 
     pub fn get_environments_for_slot(
@@ -242,6 +1159,21 @@ impl<'a> InvokeContext<'a> {
 
     /// Push a stack frame onto the invocation stack
     pub fn push(&mut self) -> Result<(), InstructionError> {
+        let stack_height = self.get_stack_height();
+        if let Some(on_push) = self.on_push.as_mut() {
+            on_push(stack_height.saturating_add(1));
+        }
+        let result = self.push_frame();
+        if result.is_err() {
+            // Unwind the hook, so that every on_push() is matched by an on_pop()
+            if let Some(on_pop) = self.on_pop.as_mut() {
+                on_pop(stack_height);
+            }
+        }
+        result
+    }
+
+    fn push_frame(&mut self) -> Result<(), InstructionError> {
         let instruction_context = self
             .transaction_context
             .get_instruction_context_at_index_in_trace(
@@ -254,6 +1186,7 @@ impl<'a> InvokeContext<'a> {
             .transaction_context
             .get_instruction_context_stack_height()
             != 0
+            && !self.reentrancy_allowlist.contains(program_id)
         {
             let contains = (0..self
                 .transaction_context
@@ -281,57 +1214,545 @@ impl<'a> InvokeContext<'a> {
                 return Err(InstructionError::ReentrancyNotAllowed);
             }
         }
+        if self.get_stack_height() >= self.max_invoke_stack_height {
+            return Err(InstructionError::CallDepth);
+        }
 
+        self.transaction_context.push()?;
+        if self.is_top_level() {
+            self.execution_interrupt.set(None);
+        }
+        // Only added once the push succeeded, so that a failed push leaves no orphaned entries
+        self.soft_limit_warned.set(false);
         self.syscall_context.push(None);
-        self.transaction_context.push()
+        self.frame_entry_compute_meter
+            .push(self.get_unspent_compute_units());
+        self.frame_nested_compute_units.push(0);
+        self.compute_reserves.borrow_mut().push(0);
+        if self.track_accounts_touched {
+            self.record_accounts_touched();
+        }
+        self.sample_consumption_timeline();
+        Ok(())
     }
 
-    /// Pop a stack frame from the invocation stack
-    fn pop(&mut self) -> Result<(), InstructionError> {
-        if let Some(Some(syscall_context)) = self.syscall_context.pop() {
-            self.traces.push(syscall_context.trace_log);
+    /// Add a sample to the consumption timeline, if it is recorded
+    fn sample_consumption_timeline(&mut self) {
+        if let Some(consumption_timeline_start) = self.consumption_timeline_start {
+            let consumed =
+                consumption_timeline_start.saturating_sub(self.get_unspent_compute_units());
+            self.consumption_timeline
+                .push((self.get_stack_height(), consumed));
         }
-        self.transaction_context.pop()
     }
 
-    /// Current height of the invocation stack, top level instructions are height
-    /// `solana_instruction::TRANSACTION_LEVEL_STACK_HEIGHT`
-    pub fn get_stack_height(&self) -> usize {
-        self.transaction_context
-            .get_instruction_context_stack_height()
+    /// Record the consumption timeline from now on, or stop recording it, disabled by default
+    ///
+    /// Enabling starts a new timeline, counting consumption from the current meter value.
+    pub fn set_record_consumption_timeline(&mut self, record_consumption_timeline: bool) {
+        self.consumption_timeline.clear();
+        self.consumption_timeline_start =
+            record_consumption_timeline.then(|| self.get_unspent_compute_units());
     }
 
-    /// Entrypoint for a cross-program invocation from a builtin program
-    pub fn native_invoke(
-        &mut self,
-        instruction: StableInstruction,
-        signers: &[Pubkey],
-    ) -> Result<(), InstructionError> {
-        let (instruction_accounts, program_indices) =
-            self.prepare_instruction(&instruction, signers)?;
-        let mut compute_units_consumed = 0;
-        self.process_instruction(
-            &instruction.data,
-            &instruction_accounts,
-            &program_indices,
-            &mut compute_units_consumed,
-            &mut ExecuteTimings::default(),
-        )?;
-        Ok(())
+    /// Samples of `(stack height, compute units consumed so far)` taken after every push and
+    /// pop while recording, e.g. to chart how the budget drained over the transaction
+    pub fn consumption_timeline(&self) -> Vec<(usize, u64)> {
+        self.consumption_timeline.clone()
     }
 
-    /// Helper to prepare for process_instruction()
-    #[allow(clippy::type_complexity)]
-    pub fn prepare_instruction(
-        &mut self,
-        instruction: &StableInstruction,
-        signers: &[Pubkey],
-    ) -> Result<(Vec<InstructionAccount>, Vec<IndexOfAccount>), InstructionError> {
-        // Finds the index of each account in the instruction by its pubkey.
-        // Then normalizes / unifies the privileges of duplicate accounts.
-        // Note: This is an O(n^2) algorithm,
-        // but performed on a very small slice and requires no heap allocations.
-        let instruction_context = self.transaction_context.get_current_instruction_context()?;
+    /// Add the instruction accounts of the current frame to the accounts touched
+    fn record_accounts_touched(&mut self) {
+        let Ok(instruction_context) = self.transaction_context.get_current_instruction_context()
+        else {
+            return;
+        };
+        let number_of_instruction_accounts =
+            instruction_context.get_number_of_instruction_accounts();
+        for instruction_account_index in 0..number_of_instruction_accounts {
+            let Ok(index_in_transaction) = instruction_context
+                .get_index_of_instruction_account_in_transaction(instruction_account_index)
+            else {
+                continue;
+            };
+            if !self.accounts_touched.contains(&index_in_transaction) {
+                self.accounts_touched.push(index_in_transaction);
+            }
+            if instruction_context
+                .is_instruction_account_writable(instruction_account_index)
+                .unwrap_or(false)
+                && !self
+                    .writable_accounts_touched
+                    .contains(&index_in_transaction)
+            {
+                self.writable_accounts_touched.push(index_in_transaction);
+            }
+        }
+    }
+
+    /// Record the instruction accounts of every frame pushed from now on, disabled by default
+    pub fn set_track_accounts_touched(&mut self, track_accounts_touched: bool) {
+        self.track_accounts_touched = track_accounts_touched;
+    }
+
+    /// Indices in the transaction of the instruction accounts of all frames pushed while
+    /// tracking was enabled, in the order they were first touched
+    pub fn accounts_touched(&self) -> &[IndexOfAccount] {
+        &self.accounts_touched
+    }
+
+    /// The subset of `accounts_touched()` which were writable in at least one frame
+    pub fn writable_accounts_touched(&self) -> &[IndexOfAccount] {
+        &self.writable_accounts_touched
+    }
+
+    /// Pop a stack frame from the invocation stack
+    fn pop(&mut self) -> Result<(), InstructionError> {
+        self.pop_with_usage().map(|_| ())
+    }
+
+    /// Pop a stack frame from the invocation stack and return the compute units it consumed
+    ///
+    /// The usage includes the compute units consumed by the nested frames of the popped frame.
+    /// Fails with `CallDepth`, leaving the context untouched, if the stack is already empty.
+    pub fn pop_with_usage(&mut self) -> Result<u64, InstructionError> {
+        if self.get_stack_height() == 0 {
+            return Err(InstructionError::CallDepth);
+        }
+        if let Some(Some(syscall_context)) = self.syscall_context.pop() {
+            self.record_trace(syscall_context.trace_log);
+        }
+        // Unused reserved compute units are given back before measuring the usage
+        if let Some(compute_reserve) = self.compute_reserves.borrow_mut().pop() {
+            let mut compute_meter = self.compute_meter.borrow_mut();
+            *compute_meter = compute_meter.saturating_add(compute_reserve);
+        }
+        let compute_units_consumed = self.get_frame_consumed_compute_units();
+        let exclusive_compute_units_consumed = self.get_frame_exclusive_consumed_compute_units();
+        self.frame_entry_compute_meter.pop();
+        self.frame_nested_compute_units.pop();
+        // The caller's own consumption excludes what the popped frame consumed
+        if let Some(nested_compute_units) = self.frame_nested_compute_units.last_mut() {
+            *nested_compute_units = nested_compute_units.saturating_add(compute_units_consumed);
+        }
+        // The frame is popped even if it turns out to be unbalanced, so the bookkeeping and
+        // `on_pop` still have to follow before the error is returned
+        let result = self.transaction_context.pop();
+        self.peak_frame_consumption = self
+            .peak_frame_consumption
+            .max(exclusive_compute_units_consumed);
+        self.sample_consumption_timeline();
+        if let Some(on_pop) = self.on_pop.as_mut() {
+            on_pop(
+                self.transaction_context
+                    .get_instruction_context_stack_height(),
+            );
+        }
+        result.map(|()| compute_units_consumed)
+    }
+
+    /// Keep the trace of the frame being popped
+    fn record_trace(&mut self, mut trace_log: Vec<[u64; 12]>) {
+        if !self.tracing_enabled {
+            return;
+        }
+        let filtered_out = self.trace_filter.is_some_and(|trace_filter| {
+            self.transaction_context
+                .get_current_instruction_context()
+                .and_then(|instruction_context| {
+                    instruction_context.get_last_program_key(self.transaction_context)
+                })
+                .map(|program_id| *program_id != trace_filter)
+                .unwrap_or(true)
+        });
+        if filtered_out {
+            // Filtered out frames still get an empty trace to keep the frame indices aligned
+            self.traces.push(Vec::new());
+            self.trace_frame_lengths.push(0);
+        } else if let Some(trace_sink) = self.trace_sink.as_mut() {
+            let frame = self.traces.len();
+            self.trace_frame_lengths.push(trace_log.len());
+            for row in trace_log {
+                trace_sink.record(frame, row);
+            }
+            self.traces.push(Vec::new());
+        } else {
+            if let Some(remaining_trace_rows) = self.remaining_trace_rows() {
+                if trace_log.len() > remaining_trace_rows {
+                    trace_log.truncate(remaining_trace_rows);
+                    self.traces_truncated = true;
+                }
+            }
+            self.recorded_trace_rows = self.recorded_trace_rows.saturating_add(trace_log.len());
+            self.trace_frame_lengths.push(trace_log.len());
+            self.traces.push(trace_log);
+        }
+    }
+
+    /// Number of trace rows which may still be kept in `traces`, if bounded
+    fn remaining_trace_rows(&self) -> Option<usize> {
+        self.max_trace_rows
+            .map(|max_trace_rows| max_trace_rows.saturating_sub(self.recorded_trace_rows))
+    }
+
+    /// Maximum height of the invocation stack
+    ///
+    /// Defaults to `SVMTransactionExecutionBudget::max_instruction_stack_depth`.
+    pub fn max_invoke_stack_height(&self) -> usize {
+        self.max_invoke_stack_height
+    }
+
+    /// Set the maximum height of the invocation stack
+    ///
+    /// The `TransactionContext` enforces its own capacity as well,
+    /// so only values up to that capacity take effect.
+    pub fn set_max_invoke_stack_height(&mut self, max_invoke_stack_height: usize) {
+        self.max_invoke_stack_height = max_invoke_stack_height;
+    }
+
+    /// Set the programs which `push()` allows to be reentered while already on the stack
+    pub fn set_reentrancy_allowlist(&mut self, reentrancy_allowlist: HashSet<Pubkey>) {
+        self.reentrancy_allowlist = reentrancy_allowlist;
+    }
+
+    /// Current height of the invocation stack, top level instructions are height
+    /// `solana_instruction::TRANSACTION_LEVEL_STACK_HEIGHT`
+    pub fn get_stack_height(&self) -> usize {
+        self.transaction_context
+            .get_instruction_context_stack_height()
+    }
+
+    /// Whether the current instruction is a top level instruction of the transaction,
+    /// as opposed to being invoked via CPI
+    pub fn is_top_level(&self) -> bool {
+        self.get_stack_height() == TRANSACTION_LEVEL_STACK_HEIGHT
+    }
+
+    /// Instruction context of the caller of the current instruction
+    ///
+    /// Returns `InstructionError::CallDepth` for top level instructions.
+    pub fn get_parent_instruction_context(&self) -> Result<&InstructionContext, InstructionError> {
+        let parent_nesting_level = self
+            .get_stack_height()
+            .checked_sub(2)
+            .ok_or(InstructionError::CallDepth)?;
+        self.transaction_context
+            .get_instruction_context_at_nesting_level(parent_nesting_level)
+    }
+
+    /// Program id of the current instruction
+    ///
+    /// Returns `InstructionError::CallDepth` if no instruction is executing.
+    pub fn current_program_id(&self) -> Result<&Pubkey, InstructionError> {
+        self.transaction_context
+            .get_current_instruction_context()?
+            .get_last_program_key(self.transaction_context)
+    }
+
+    /// Number of accounts of the transaction
+    pub fn total_accounts(&self) -> usize {
+        self.transaction_context.get_number_of_accounts() as usize
+    }
+
+    /// Number of instruction accounts of the current instruction, including duplicates
+    ///
+    /// Returns `InstructionError::CallDepth` if no instruction is executing.
+    pub fn instruction_account_count(&self) -> Result<usize, InstructionError> {
+        self.transaction_context
+            .get_current_instruction_context()
+            .map(|instruction_context| {
+                instruction_context.get_number_of_instruction_accounts() as usize
+            })
+    }
+
+    /// Instruction data of the current instruction
+    ///
+    /// Returns `InstructionError::CallDepth` if no instruction is executing.
+    pub fn instruction_data(&self) -> Result<&[u8], InstructionError> {
+        self.transaction_context
+            .get_current_instruction_context()
+            .map(|instruction_context| instruction_context.get_instruction_data())
+    }
+
+    /// Index in the transaction of the program account of the current instruction
+    ///
+    /// Returns `InstructionError::CallDepth` if no instruction is executing and
+    /// `InstructionError::MissingAccount` if the program account is not in the transaction.
+    pub fn find_program_account_index(&self) -> Result<IndexOfAccount, InstructionError> {
+        let program_id = self
+            .transaction_context
+            .get_current_instruction_context()?
+            .get_last_program_key(self.transaction_context)
+            .map_err(|_| InstructionError::MissingAccount)?;
+        self.transaction_context
+            .find_index_of_account(program_id)
+            .ok_or(InstructionError::MissingAccount)
+    }
+
+    /// Names of the syscalls the program of the current instruction can call
+    ///
+    /// These are the syscalls registered in the runtime environment it was loaded with, in the
+    /// order of `SYSCALL_NAMES`, which unknown registrations are not part of. Builtins do not
+    /// call syscalls, so they have none. Returns `InstructionError::CallDepth` if no
+    /// instruction is executing and `InstructionError::UnsupportedProgramId` if the program is
+    /// not in the program cache.
+    pub fn available_syscalls(&self) -> Result<Vec<&'static str>, InstructionError> {
+        let program_id = self.current_program_id()?;
+        let entry = self
+            .program_cache_for_tx_batch
+            .find(program_id)
+            .ok_or(InstructionError::UnsupportedProgramId)?;
+        Ok(match &entry.program {
+            ProgramCacheEntryType::Loaded(executable) => {
+                let function_registry = executable.get_loader().get_function_registry();
+                SYSCALL_NAMES
+                    .iter()
+                    .copied()
+                    .filter(|name| function_registry.lookup_by_name(name.as_bytes()).is_some())
+                    .collect()
+            }
+            _ => Vec::new(),
+        })
+    }
+
+    /// Loader owning the program account of the current instruction
+    ///
+    /// Returns `InstructionError::UnsupportedProgramId` if the owner is not a loader of
+    /// programs executed in the VM, e.g. for builtins owned by the native loader.
+    pub fn get_loader_id(&self) -> Result<Pubkey, InstructionError> {
+        let instruction_context = self.transaction_context.get_current_instruction_context()?;
+        let program_account =
+            instruction_context.try_borrow_last_program_account(self.transaction_context)?;
+        let owner_id = program_account.get_owner();
+        if is_bpf_loader(owner_id) {
+            Ok(*owner_id)
+        } else {
+            Err(InstructionError::UnsupportedProgramId)
+        }
+    }
+
+    /// Program id and data of the return data set last in the transaction
+    pub fn get_return_data(&self) -> (Pubkey, &[u8]) {
+        let (program_id, data) = self.transaction_context.get_return_data();
+        (*program_id, data)
+    }
+
+    /// Set the return data of the current program, charged like the `sol_set_return_data`
+    /// syscall
+    ///
+    /// If `data` is longer than [MAX_RETURN_DATA] nothing is charged, the syscall's
+    /// `ReturnDataTooLarge` error is logged and, as for a program failing that way,
+    /// `InstructionError::ProgramFailedToComplete` is returned. Without a current program
+    /// nothing is charged either.
+    pub fn set_return_data(&mut self, data: Vec<u8>) -> Result<(), InstructionError> {
+        let program_id = *self.current_program_id()?;
+        if data.len() > MAX_RETURN_DATA {
+            ic_msg!(
+                self,
+                "Return data too large ({} > {})",
+                data.len(),
+                MAX_RETURN_DATA,
+            );
+            return Err(InstructionError::ProgramFailedToComplete);
+        }
+        self.consume_for_syscall(
+            "sol_set_return_data",
+            self.cost_model.return_data_cost(data.len()),
+        )?;
+        self.transaction_context.set_return_data(program_id, data)
+    }
+
+    /// Entrypoint for a cross-program invocation from a builtin program
+    ///
+    /// Composes `prepare_instruction()` and `process_instruction()`, so account privileges
+    /// and signers are validated the same way as in a CPI and the pushed frame is always
+    /// popped again, even if the callee fails.
+    pub fn native_invoke(
+        &mut self,
+        instruction: StableInstruction,
+        signers: &[Pubkey],
+    ) -> Result<(), InstructionError> {
+        let (instruction_accounts, program_indices) =
+            self.prepare_instruction(&instruction, signers)?;
+        let mut compute_units_consumed = 0;
+        // The callee is measured on its own, so pause the measurement of the caller
+        if let Some(execute_time) = self.execute_time.as_mut() {
+            execute_time.stop();
+            self.timings.execute_us += execute_time.as_us();
+        }
+        let result = self.process_instruction(
+            &instruction.data,
+            &instruction_accounts,
+            &program_indices,
+            &mut compute_units_consumed,
+            &mut ExecuteTimings::default(),
+        );
+        if self.execute_time.is_some() {
+            self.execute_time = Some(Measure::start("execute"));
+        }
+        result
+    }
+
+    /// Helper to prepare for process_instruction()
+    #[allow(clippy::type_complexity)]
+    pub fn prepare_instruction(
+        &mut self,
+        instruction: &StableInstruction,
+        signers: &[Pubkey],
+    ) -> Result<(Vec<InstructionAccount>, Vec<IndexOfAccount>), InstructionError> {
+        self.validate_instruction(instruction, signers, self.log_collector.as_ref())
+    }
+
+    /// Helper to prepare a batch of instructions for process_instruction()
+    ///
+    /// Validates all instructions up front against the current frame, in order, looking up
+    /// the frame only once for the whole batch. Fails on the first invalid instruction with
+    /// its index in `instructions`, which is logged as well, or with index 0 if there is no
+    /// current frame.
+    pub fn prepare_instructions(
+        &mut self,
+        instructions: &[StableInstruction],
+        signers: &[Pubkey],
+    ) -> Result<Vec<PreparedInstruction>, (usize, InstructionError)> {
+        let instruction_context = self
+            .transaction_context
+            .get_current_instruction_context()
+            .map_err(|err| (0, err))?;
+        instructions
+            .iter()
+            .enumerate()
+            .map(|(index, instruction)| {
+                self.validate_instruction_in_frame(
+                    instruction_context,
+                    instruction,
+                    signers,
+                    self.log_collector.as_ref(),
+                )
+                .map(
+                    |(instruction_accounts, program_indices)| PreparedInstruction {
+                        instruction_accounts,
+                        program_indices,
+                    },
+                )
+                .map_err(|err| {
+                    ic_msg!(self, "Instruction {} of the batch failed to prepare", index);
+                    (index, err)
+                })
+            })
+            .collect()
+    }
+
+    /// Runs the same validation as prepare_instruction() without preparing a frame
+    ///
+    /// Returns the exact same errors, e.g. for simulators which need to behave identically,
+    /// but logs nothing, so that the transaction logs are not affected.
+    pub fn prepare_instruction_dry_run(
+        &self,
+        instruction: &StableInstruction,
+        signers: &[Pubkey],
+    ) -> Result<(), InstructionError> {
+        self.validate_instruction(instruction, signers, None)
+            .map(|_| ())
+    }
+
+    /// Check that none of the writable `instruction_accounts` is executable
+    ///
+    /// Fails with `InstructionError::ExecutableModified` on the first writable executable
+    /// account, as such an account can not be modified by the callee anyway.
+    pub fn verify_writable_not_executable(
+        &self,
+        instruction_accounts: &[InstructionAccount],
+    ) -> Result<(), InstructionError> {
+        for instruction_account in instruction_accounts
+            .iter()
+            .filter(|instruction_account| instruction_account.is_writable)
+        {
+            let account = self
+                .transaction_context
+                .accounts()
+                .try_borrow(instruction_account.index_in_transaction)?;
+            if account.executable() {
+                ic_msg!(
+                    self,
+                    "Writable account {} is executable",
+                    self.transaction_context
+                        .get_key_of_account_at_index(instruction_account.index_in_transaction)?,
+                );
+                return Err(InstructionError::ExecutableModified);
+            }
+        }
+        Ok(())
+    }
+
+    /// Estimate the compute units a CPI of `instruction` is charged for serialization
+    ///
+    /// Mirrors the charges of the CPI syscalls: `invoke_units`, plus the instruction data and
+    /// one `AccountMeta` per account, plus the data of each distinct account, all at
+    /// `cpi_bytes_per_unit`. Accounts missing from the transaction are not counted. This is an
+    /// estimate, the actual charge differs if account data is resized before the CPI.
+    pub fn estimate_cpi_serialization_cost(&self, instruction: &StableInstruction) -> u64 {
+        let cpi_bytes_per_unit = self.execution_cost.cpi_bytes_per_unit;
+        let instruction_bytes = instruction
+            .accounts
+            .len()
+            .saturating_mul(size_of::<AccountMeta>())
+            .saturating_add(instruction.data.len());
+        let mut indices_in_transaction = Vec::with_capacity(instruction.accounts.len());
+        let mut account_data_cost = 0u64;
+        for account_meta in instruction.accounts.iter() {
+            let Some(index_in_transaction) = self
+                .transaction_context
+                .find_index_of_account(&account_meta.pubkey)
+            else {
+                continue;
+            };
+            if indices_in_transaction.contains(&index_in_transaction) {
+                continue;
+            }
+            indices_in_transaction.push(index_in_transaction);
+            let data_len = self
+                .transaction_context
+                .accounts()
+                .try_borrow(index_in_transaction)
+                .map(|account| account.data().len())
+                .unwrap_or_default();
+            account_data_cost = account_data_cost.saturating_add(
+                (data_len as u64)
+                    .checked_div(cpi_bytes_per_unit)
+                    .unwrap_or(u64::MAX),
+            );
+        }
+        (instruction_bytes as u64)
+            .checked_div(cpi_bytes_per_unit)
+            .unwrap_or(u64::MAX)
+            .saturating_add(self.execution_cost.invoke_units)
+            .saturating_add(account_data_cost)
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn validate_instruction(
+        &self,
+        instruction: &StableInstruction,
+        signers: &[Pubkey],
+        log_collector: Option<&Rc<RefCell<LogCollector>>>,
+    ) -> Result<(Vec<InstructionAccount>, Vec<IndexOfAccount>), InstructionError> {
+        let instruction_context = self.transaction_context.get_current_instruction_context()?;
+        self.validate_instruction_in_frame(instruction_context, instruction, signers, log_collector)
+    }
+
+    /// Like `validate_instruction()`, against an already looked up `instruction_context`
+    #[allow(clippy::type_complexity)]
+    fn validate_instruction_in_frame(
+        &self,
+        instruction_context: &InstructionContext,
+        instruction: &StableInstruction,
+        signers: &[Pubkey],
+        log_collector: Option<&Rc<RefCell<LogCollector>>>,
+    ) -> Result<(Vec<InstructionAccount>, Vec<IndexOfAccount>), InstructionError> {
+        // Finds the index of each account in the instruction by its pubkey.
+        // Then normalizes / unifies the privileges of duplicate accounts.
+        // Note: This is an O(n^2) algorithm,
+        // but performed on a very small slice and requires no heap allocations.
         let mut deduplicated_instruction_accounts: Vec<InstructionAccount> = Vec::new();
         let mut duplicate_indicies = Vec::with_capacity(instruction.accounts.len() as usize);
         for (instruction_account_index, account_meta) in instruction.accounts.iter().enumerate() {
@@ -339,8 +1760,8 @@ impl<'a> InvokeContext<'a> {
                 .transaction_context
                 .find_index_of_account(&account_meta.pubkey)
                 .ok_or_else(|| {
-                    ic_msg!(
-                        self,
+                    ic_logger_msg!(
+                        log_collector,
                         "Instruction references an unknown account {}",
                         account_meta.pubkey,
                     );
@@ -366,8 +1787,8 @@ impl<'a> InvokeContext<'a> {
                         &account_meta.pubkey,
                     )
                     .ok_or_else(|| {
-                        ic_msg!(
-                            self,
+                        ic_logger_msg!(
+                            log_collector,
                             "Instruction references an unknown account {}",
                             account_meta.pubkey,
                         );
@@ -391,10 +1812,15 @@ impl<'a> InvokeContext<'a> {
 
             // Readonly in caller cannot become writable in callee
             if instruction_account.is_writable && !borrowed_account.is_writable() {
-                ic_msg!(
-                    self,
-                    "{}'s writable privilege escalated",
-                    borrowed_account.get_key(),
+                ic_logger_msg!(
+                    log_collector,
+                    "{}",
+                    PrivilegeEscalation {
+                        pubkey: borrowed_account.get_key(),
+                        instruction_account,
+                        privilege: AccountPrivilege::Writable,
+                        caller: false,
+                    },
                 );
                 return Err(InstructionError::PrivilegeEscalation);
             }
@@ -404,10 +1830,15 @@ impl<'a> InvokeContext<'a> {
             if instruction_account.is_signer
                 && !(borrowed_account.is_signer() || signers.contains(borrowed_account.get_key()))
             {
-                ic_msg!(
-                    self,
-                    "{}'s signer privilege escalated",
-                    borrowed_account.get_key()
+                ic_logger_msg!(
+                    log_collector,
+                    "{}",
+                    PrivilegeEscalation {
+                        pubkey: borrowed_account.get_key(),
+                        instruction_account,
+                        privilege: AccountPrivilege::Signer,
+                        caller: false,
+                    },
                 );
                 return Err(InstructionError::PrivilegeEscalation);
             }
@@ -428,14 +1859,14 @@ impl<'a> InvokeContext<'a> {
             self.transaction_context
                 .find_index_of_program_account(&callee_program_id)
                 .ok_or_else(|| {
-                    ic_msg!(self, "Unknown program {}", callee_program_id);
+                    ic_logger_msg!(log_collector, "Unknown program {}", callee_program_id);
                     InstructionError::MissingAccount
                 })?
         } else {
             let program_account_index = instruction_context
                 .find_index_of_instruction_account(self.transaction_context, &callee_program_id)
                 .ok_or_else(|| {
-                    ic_msg!(self, "Unknown program {}", callee_program_id);
+                    ic_logger_msg!(log_collector, "Unknown program {}", callee_program_id);
                     InstructionError::MissingAccount
                 })?;
             let borrowed_program_account = instruction_context
@@ -446,7 +1877,11 @@ impl<'a> InvokeContext<'a> {
                 .remove_accounts_executable_flag_checks
                 && !borrowed_program_account.is_executable()
             {
-                ic_msg!(self, "Account {} is not executable", callee_program_id);
+                ic_logger_msg!(
+                    log_collector,
+                    "Account {} is not executable",
+                    callee_program_id
+                );
                 return Err(InstructionError::AccountNotExecutable);
             }
             borrowed_program_account.get_index_in_transaction()
@@ -475,6 +1910,40 @@ impl<'a> InvokeContext<'a> {
             .and(self.pop())
     }
 
+    /// Like `process_instruction()`, but attaches the program id, compute units consumed
+    /// and stack height to the error
+    pub fn process_instruction_detailed(
+        &mut self,
+        instruction_data: &[u8],
+        instruction_accounts: &[InstructionAccount],
+        program_indices: &[IndexOfAccount],
+        compute_units_consumed: &mut u64,
+        timings: &mut ExecuteTimings,
+    ) -> Result<(), DetailedInstructionError> {
+        let stack_height = self.get_stack_height().saturating_add(1);
+        self.process_instruction(
+            instruction_data,
+            instruction_accounts,
+            program_indices,
+            compute_units_consumed,
+            timings,
+        )
+        .map_err(|error| DetailedInstructionError {
+            error,
+            program_id: program_indices
+                .last()
+                .and_then(|index_in_transaction| {
+                    self.transaction_context
+                        .get_key_of_account_at_index(*index_in_transaction)
+                        .ok()
+                })
+                .copied()
+                .unwrap_or_default(),
+            compute_consumed: *compute_units_consumed,
+            stack_height,
+        })
+    }
+
     /// Processes a precompile instruction
     pub fn process_precompile<'ix_data>(
         &mut self,
@@ -518,11 +1987,7 @@ impl<'a> InvokeContext<'a> {
                 .get_feature_set()
                 .remove_accounts_executable_flag_checks
             {
-                if bpf_loader_deprecated::check_id(owner_id)
-                    || bpf_loader::check_id(owner_id)
-                    || bpf_loader_upgradeable::check_id(owner_id)
-                    || loader_v4::check_id(owner_id)
-                {
+                if is_bpf_loader(owner_id) {
                     *owner_id
                 } else {
                     return Err(InstructionError::UnsupportedProgramId);
@@ -538,6 +2003,39 @@ impl<'a> InvokeContext<'a> {
             .program_cache_for_tx_batch
             .find(&builtin_id)
             .ok_or(InstructionError::UnsupportedProgramId)?;
+        if let Some(vm_backend) = self.vm_backend.clone() {
+            entry.ix_usage_counter.fetch_add(1, Ordering::Relaxed);
+            let program_id = *instruction_context.get_last_program_key(self.transaction_context)?;
+            self.transaction_context
+                .set_return_data(program_id, Vec::new())?;
+            let logger = self.get_log_collector();
+            stable_log::program_invoke(&logger, &program_id, self.get_stack_height());
+            let pre_remaining_units = self.get_remaining_compute_units();
+            let started_execution_deadline = self.start_execution_deadline();
+            let result = vm_backend.execute(&entry, self);
+            let result = match self.poll_execution_interrupt() {
+                Some(execution_interrupt) => Err(InstructionError::from(execution_interrupt)),
+                None => result,
+            };
+            match &result {
+                Ok(()) => stable_log::program_success(&logger, &program_id),
+                Err(err) => stable_log::program_failure(&logger, &program_id, err),
+            }
+            if started_execution_deadline {
+                self.execution_deadline = None;
+            }
+            // Measured on the meter, so that a backend can not under-report its consumption
+            let post_remaining_units = self.get_remaining_compute_units();
+            *compute_units_consumed = pre_remaining_units.saturating_sub(post_remaining_units);
+            if builtin_id == program_id && result.is_ok() && *compute_units_consumed == 0 {
+                return Err(InstructionError::BuiltinProgramsMustConsumeComputeUnits);
+            }
+            timings
+                .execute_accessories
+                .process_instructions
+                .process_executable_chain_us += process_executable_chain_time.end_as_us();
+            return result;
+        }
         let function = match &entry.program {
             ProgramCacheEntryType::Builtin(program) => program
                 .get_function_registry()
@@ -553,13 +2051,21 @@ impl<'a> InvokeContext<'a> {
             .set_return_data(program_id, Vec::new())?;
         let logger = self.get_log_collector();
         stable_log::program_invoke(&logger, &program_id, self.get_stack_height());
-        let pre_remaining_units = self.get_remaining();
+        let pre_remaining_units = self.get_remaining_compute_units();
+        let started_execution_deadline = self.start_execution_deadline();
         // In program-runtime v2 we will create this VM instance only once per transaction.
         // `program_runtime_environment_v2.get_config()` will be used instead of `mock_config`.
         // For now, only built-ins are invoked from here, so the VM and its Config are irrelevant.
         let mock_config = Config::default();
         let empty_memory_mapping =
             MemoryMapping::new(Vec::new(), &mock_config, SBPFVersion::V0).unwrap();
+        // Loaders measure the execution of the programs they load themselves
+        let measure_execute_time = self.measure_execute_time && builtin_id == program_id;
+        let outer_execute_time = if measure_execute_time {
+            self.execute_time.replace(Measure::start("execute"))
+        } else {
+            None
+        };
         let mut vm = EbpfVm::new(
             self.program_cache_for_tx_batch
                 .environments
@@ -572,28 +2078,44 @@ impl<'a> InvokeContext<'a> {
             0,
         );
         vm.invoke_function(function);
-        let result = match vm.program_result {
-            ProgramResult::Ok(_) => {
-                stable_log::program_success(&logger, &program_id);
-                Ok(())
+        if measure_execute_time {
+            let execute_time = std::mem::replace(&mut self.execute_time, outer_execute_time);
+            if let Some(execute_time) = execute_time {
+                self.timings.execute_us += execute_time.end_as_us();
             }
-            ProgramResult::Err(ref err) => {
-                if let EbpfError::SyscallError(syscall_error) = err {
-                    if let Some(instruction_err) = syscall_error.downcast_ref::<InstructionError>()
-                    {
-                        stable_log::program_failure(&logger, &program_id, instruction_err);
-                        Err(instruction_err.clone())
+        }
+        let result = if let Some(execution_interrupt) = self.poll_execution_interrupt() {
+            let err = InstructionError::from(execution_interrupt);
+            stable_log::program_failure(&logger, &program_id, &err);
+            Err(err)
+        } else {
+            match vm.program_result {
+                ProgramResult::Ok(_) => {
+                    stable_log::program_success(&logger, &program_id);
+                    Ok(())
+                }
+                ProgramResult::Err(ref err) => {
+                    if let EbpfError::SyscallError(syscall_error) = err {
+                        if let Some(instruction_err) =
+                            syscall_error.downcast_ref::<InstructionError>()
+                        {
+                            stable_log::program_failure(&logger, &program_id, instruction_err);
+                            Err(instruction_err.clone())
+                        } else {
+                            stable_log::program_failure(&logger, &program_id, syscall_error);
+                            Err(InstructionError::ProgramFailedToComplete)
+                        }
                     } else {
-                        stable_log::program_failure(&logger, &program_id, syscall_error);
+                        stable_log::program_failure(&logger, &program_id, err);
                         Err(InstructionError::ProgramFailedToComplete)
                     }
-                } else {
-                    stable_log::program_failure(&logger, &program_id, err);
-                    Err(InstructionError::ProgramFailedToComplete)
                 }
             }
         };
-        let post_remaining_units = self.get_remaining();
+        if started_execution_deadline {
+            self.execution_deadline = None;
+        }
+        let post_remaining_units = self.get_remaining_compute_units();
         *compute_units_consumed = pre_remaining_units.saturating_sub(post_remaining_units);
 
         if builtin_id == program_id && result.is_ok() && *compute_units_consumed == 0 {
@@ -612,49 +2134,533 @@ impl<'a> InvokeContext<'a> {
         self.log_collector.clone()
     }
 
-    /// Consume compute units
-    pub fn consume_checked(&self, amount: u64) -> Result<(), Box<dyn std::error::Error>> {
-        let mut compute_meter = self.compute_meter.borrow_mut();
-        let exceeded = *compute_meter < amount;
-        *compute_meter = compute_meter.saturating_sub(amount);
-        if exceeded {
-            return Err(Box::new(InstructionError::ComputationalBudgetExceeded));
-        }
-        Ok(())
-    }
-
-    /// Set compute units
+    /// Install a new LogCollector, or none, and return the previous one
     ///
-    /// Only use for tests and benchmarks
-    pub fn mock_set_remaining(&self, remaining: u64) {
-        *self.compute_meter.borrow_mut() = remaining;
+    /// Messages logged from now on go to the new collector. Logging is charged the same
+    /// regardless of which collector, if any, is installed.
+    pub fn set_log_collector(
+        &mut self,
+        log_collector: Option<Rc<RefCell<LogCollector>>>,
+    ) -> Option<Rc<RefCell<LogCollector>>> {
+        std::mem::replace(&mut self.log_collector, log_collector)
     }
 
-    /// Get this invocation's compute budget
-    pub fn get_compute_budget(&self) -> &SVMTransactionExecutionBudget {
-        &self.compute_budget
+    /// Log a message, charged like the `sol_log_` syscall
+    ///
+    /// The compute units are consumed even if there is no `LogCollector`.
+    pub fn log(&self, message: &str) -> Result<(), InstructionError> {
+        self.consume_for_syscall("sol_log_", self.cost_model.log_cost(message.len()))?;
+        ic_msg!(self, message);
+        Ok(())
     }
 
-    /// Get this invocation's compute budget
-    pub fn get_execution_cost(&self) -> &SVMTransactionExecutionCost {
-        &self.execution_cost
+    /// Log a formatted message, charged like `log()`
+    pub fn log_fmt(&self, args: fmt::Arguments) -> Result<(), InstructionError> {
+        self.log(&fmt::format(args))
+    }
+
+    /// Log data fields, charged like the `sol_log_data` syscall
+    ///
+    /// The syscall cost is charged once for the call and once per field, plus the total length
+    /// of the fields. Like `log()`, this does not depend on the presence of a `LogCollector`.
+    /// If the cost exceeds the remaining compute units the meter is drained, nothing is logged
+    /// and `InstructionError::ComputationalBudgetExceeded` is returned.
+    pub fn log_data(&self, fields: &[&[u8]]) -> Result<(), InstructionError> {
+        let syscall_cost = self.cost_model.syscall_cost("sol_log_data");
+        let cost = fields.iter().fold(syscall_cost, |cost, field| {
+            cost.saturating_add(syscall_cost)
+                .saturating_add(field.len() as u64)
+        });
+        self.consume_for_syscall("sol_log_data", cost)?;
+        stable_log::program_data(&self.log_collector, fields);
+        Ok(())
+    }
+
+    /// Get the compute units remaining in this invocation's compute meter
+    ///
+    /// This is a snapshot, the value may change on the next syscall or instruction.
+    pub fn get_remaining_compute_units(&self) -> u64 {
+        *self.compute_meter.borrow()
+    }
+
+    /// Get the compute units consumed so far against the compute unit limit
+    ///
+    /// This is a snapshot, the value may change on the next syscall or instruction.
+    pub fn get_consumed_compute_units(&self) -> u64 {
+        self.compute_budget
+            .compute_unit_limit
+            .saturating_sub(self.get_remaining_compute_units())
+    }
+
+    /// Remaining compute units as a fraction of the compute unit limit, from 0.0 to 1.0
+    ///
+    /// Returns 0.0 if the compute unit limit is zero.
+    pub fn remaining_as_fraction(&self) -> f64 {
+        let compute_unit_limit = self.compute_budget.compute_unit_limit;
+        if compute_unit_limit == 0 {
+            return 0.0;
+        }
+        (self.get_remaining_compute_units() as f64 / compute_unit_limit as f64).min(1.0)
+    }
+
+    /// Consume compute units
+    pub fn consume_checked(&self, amount: u64) -> Result<(), Box<dyn std::error::Error>> {
+        self.try_consume(amount)
+            .map(|_remaining| ())
+            .map_err(|err| Box::new(err) as Box<dyn std::error::Error>)
+    }
+
+    /// Consume compute units and return the compute units remaining afterwards
+    ///
+    /// If `amount` exceeds the remaining compute units the meter is drained to zero
+    /// and `InstructionError::ComputationalBudgetExceeded` is returned.
+    pub fn try_consume(&self, amount: u64) -> Result<u64, InstructionError> {
+        // Exhaustion saturates the meter at zero, so a meter above its ceiling can only be
+        // caused by a metering bug, which must not be mistaken for running out of budget
+        let invariant_ok = self.compute_meter_invariant_ok();
+        debug_assert!(invariant_ok, "compute meter exceeds its ceiling");
+        if !invariant_ok {
+            ic_msg!(
+                self,
+                "Compute meter inconsistent: {} remaining exceeds the ceiling of {}",
+                self.get_remaining_compute_units(),
+                self.compute_meter_ceiling.get(),
+            );
+            return Err(InstructionError::GenericError);
+        }
+        let mut compute_meter = self.compute_meter.borrow_mut();
+        let exceeded = *compute_meter < amount;
+        *compute_meter = compute_meter.saturating_sub(amount);
+        let remaining = *compute_meter;
+        if let Some(consume_hook) = self.consume_hook.borrow_mut().as_mut() {
+            consume_hook(amount, remaining);
+        }
+        drop(compute_meter);
+        self.warn_on_soft_limit();
+        if exceeded {
+            return Err(InstructionError::ComputationalBudgetExceeded);
+        }
+        if let Some(execution_interrupt) = self.poll_execution_interrupt() {
+            return Err(execution_interrupt.into());
+        }
+        Ok(remaining)
+    }
+
+    /// Whether anything observes consumption, which the VM meter path checks first so that
+    /// it costs no more than deducting the compute units if nothing is configured
+    fn is_consumption_monitored(&self) -> bool {
+        self.soft_limit_fraction.is_some()
+            || self.can_interrupt_execution()
+            || self.consume_hook.borrow().is_some()
+    }
+
+    /// Whether a frame compute unit cap or an execution deadline is set
+    fn can_interrupt_execution(&self) -> bool {
+        self.frame_cu_cap.is_some() || self.execution_deadline.is_some()
+    }
+
+    /// Why the current frame has to be stopped, if it has to
+    fn check_execution_interrupt(&self) -> Option<ExecutionInterrupt> {
+        if !self.can_interrupt_execution() {
+            return None;
+        }
+        if let Some(frame_cu_cap) = self.frame_cu_cap {
+            if self.get_frame_exclusive_consumed_compute_units() > frame_cu_cap {
+                return Some(ExecutionInterrupt::FrameCuCapExceeded);
+            }
+        }
+        let timed_out = self
+            .execution_deadline
+            .is_some_and(|execution_deadline| (self.time_source)() > execution_deadline);
+        if timed_out {
+            return Some(ExecutionInterrupt::Timeout);
+        }
+        None
+    }
+
+    /// Like `check_execution_interrupt()` but records the interrupt, logging it the first time
+    fn poll_execution_interrupt(&self) -> Option<ExecutionInterrupt> {
+        let execution_interrupt = self.check_execution_interrupt()?;
+        let previous_interrupt = self.execution_interrupt.replace(Some(execution_interrupt));
+        if previous_interrupt != Some(execution_interrupt) {
+            match execution_interrupt {
+                ExecutionInterrupt::FrameCuCapExceeded => ic_msg!(
+                    self,
+                    "Frame compute unit cap of {} exceeded",
+                    self.frame_cu_cap.unwrap_or_default(),
+                ),
+                ExecutionInterrupt::Timeout => ic_msg!(
+                    self,
+                    "Execution timeout of {} ms exceeded",
+                    self.environment_config
+                        .execution_timeout
+                        .unwrap_or_default()
+                        .as_millis(),
+                ),
+            }
+        }
+        Some(execution_interrupt)
+    }
+
+    /// Why an instruction was stopped since the last top level frame was pushed, if it was
+    pub fn execution_interrupt(&self) -> Option<ExecutionInterrupt> {
+        self.execution_interrupt.get()
+    }
+
+    /// Start the deadline of the execution timeout, unless the program is invoked by another
+    /// program, whose deadline it shares. Returns whether the deadline was started.
+    fn start_execution_deadline(&mut self) -> bool {
+        if self.execution_deadline.is_some() {
+            return false;
+        }
+        self.execution_deadline = self
+            .environment_config
+            .execution_timeout
+            .and_then(|execution_timeout| (self.time_source)().checked_add(execution_timeout));
+        true
+    }
+
+    /// Replace the clock the execution timeout is measured with, e.g. by a mock clock in tests
+    pub fn set_time_source(&mut self, time_source: Rc<dyn Fn() -> Instant>) {
+        self.time_source = time_source;
+    }
+
+    /// Compute units consumed since the current frame was pushed, including nested frames
+    ///
+    /// Reserved compute units only count as consumed once spent via `consume_reserved()`,
+    /// compute units withheld by a `ComputeBudgetGuard` never do.
+    pub fn get_frame_consumed_compute_units(&self) -> u64 {
+        self.frame_entry_compute_meter
+            .last()
+            .map(|unspent_on_entry| {
+                unspent_on_entry.saturating_sub(self.get_unspent_compute_units())
+            })
+            .unwrap_or(0)
+    }
+
+    /// Compute units which were not consumed yet: the remaining ones, those reserved by the
+    /// frames on the stack and those withheld by the outstanding `ComputeBudgetGuard`s
+    fn get_unspent_compute_units(&self) -> u64 {
+        let remaining_and_withheld = self
+            .get_remaining_compute_units()
+            .saturating_add(self.compute_meter_withheld.get());
+        self.compute_reserves
+            .borrow()
+            .iter()
+            .fold(remaining_and_withheld, |unspent, compute_reserve| {
+                unspent.saturating_add(*compute_reserve)
+            })
+    }
+
+    /// Compute units consumed since the current frame was pushed, excluding nested frames
+    pub fn get_frame_exclusive_consumed_compute_units(&self) -> u64 {
+        let nested_compute_units = self.frame_nested_compute_units.last().copied();
+        self.get_frame_consumed_compute_units()
+            .saturating_sub(nested_compute_units.unwrap_or(0))
+    }
+
+    /// Most compute units consumed by a single frame popped so far
+    ///
+    /// Unlike the usage measured on pop, the consumption of a frame excludes its nested
+    /// frames, so the peak points at the CPI level which is the hot spot.
+    pub fn peak_frame_consumption(&self) -> u64 {
+        self.peak_frame_consumption
+    }
+
+    /// Limit the compute units a single frame may consume, or lift the limit if `None`
+    ///
+    /// Only the consumption of the frame itself counts against the cap, not that of the
+    /// nested frames it invoked, so a single CPI can not monopolize the budget while its
+    /// caller may still continue once it returned. The cap is checked on every consumption:
+    /// `try_consume()` fails with `InstructionError::ComputationalBudgetExceeded`, after
+    /// logging that the cap was exceeded, and the VM is stopped by reporting no compute
+    /// units remaining. See `execution_interrupt()` to tell the cap from an exhausted meter.
+    pub fn set_frame_cu_cap(&mut self, frame_cu_cap: Option<u64>) {
+        self.frame_cu_cap = frame_cu_cap;
+    }
+
+    /// Consume compute units like `try_consume()` and attribute them to `label`
+    ///
+    /// Only the compute units actually deducted are attributed, so if `amount` exceeds
+    /// the remaining compute units, the label is charged with what was remaining, and if
+    /// `try_consume()` fails without deducting anything, the label is charged nothing.
+    pub fn consume_with_label(
+        &self,
+        amount: u64,
+        label: &'static str,
+    ) -> Result<(), InstructionError> {
+        let remaining_before = self.get_remaining_compute_units();
+        let result = self.try_consume(amount);
+        let consumed = remaining_before.saturating_sub(self.get_remaining_compute_units());
+        let mut compute_breakdown = self.compute_breakdown.borrow_mut();
+        let compute_units = compute_breakdown.entry(label).or_default();
+        *compute_units = compute_units.saturating_add(consumed);
+        result.map(|_remaining| ())
+    }
+
+    /// Compute units charged via `consume_with_label()`, keyed by label
+    pub fn compute_breakdown(&self) -> Ref<'_, BTreeMap<&'static str, u64>> {
+        self.compute_breakdown.borrow()
+    }
+
+    /// Log a warning the first time in a frame that the consumed compute units
+    /// cross the soft limit fraction of the compute unit limit
+    fn warn_on_soft_limit(&self) {
+        let Some(soft_limit_fraction) = self.soft_limit_fraction else {
+            return;
+        };
+        if self.soft_limit_warned.get() {
+            return;
+        }
+        let compute_unit_limit = self.compute_budget.compute_unit_limit;
+        // Reserved and withheld compute units are not consumed
+        let consumed = compute_unit_limit.saturating_sub(self.get_unspent_compute_units());
+        if consumed as f64 >= compute_unit_limit as f64 * soft_limit_fraction {
+            self.soft_limit_warned.set(true);
+            ic_msg!(
+                self,
+                "Compute unit soft limit reached: {} of {} compute units consumed",
+                consumed,
+                compute_unit_limit,
+            );
+        }
+    }
+
+    /// Warn once per frame when the consumed compute units cross the given
+    /// fraction of the compute unit limit, or never if `None`
+    pub fn set_soft_limit_fraction(&mut self, soft_limit_fraction: Option<f64>) {
+        self.soft_limit_fraction = soft_limit_fraction;
+    }
+
+    /// Lower the remaining compute units to at most `cu` until the returned guard is dropped
+    ///
+    /// The compute units above `cu` are withheld and given back when the guard is dropped,
+    /// so consumption while the guard is alive counts against both the reduced and the
+    /// outer budget. Guards must be dropped in the reverse order of their creation. The
+    /// guard shares the compute meter instead of borrowing the context, so instructions can
+    /// be processed under the reduced budget. Withheld compute units do not count as consumed,
+    /// and a guard outliving `reset()` gives back no more than the meter's ceiling allows.
+    pub fn with_reduced_budget(&self, cu: u64) -> ComputeBudgetGuard {
+        let mut compute_meter = self.compute_meter.borrow_mut();
+        let withheld = compute_meter.saturating_sub(cu);
+        *compute_meter = compute_meter.saturating_sub(withheld);
+        let compute_meter_withheld = self.compute_meter_withheld.get();
+        self.compute_meter_withheld
+            .set(compute_meter_withheld.saturating_add(withheld));
+        ComputeBudgetGuard {
+            compute_meter: Rc::clone(&self.compute_meter),
+            compute_meter_ceiling: Rc::clone(&self.compute_meter_ceiling),
+            compute_meter_withheld: Rc::clone(&self.compute_meter_withheld),
+            withheld,
+        }
+    }
+
+    /// Set `amount` compute units aside for the current frame, e.g. for cleanup
+    ///
+    /// The reserved compute units can only be spent via `consume_reserved()`, not by any
+    /// other consumption. Whatever is left of the reserve is given back when the frame is
+    /// popped. Fails with `InstructionError::ComputationalBudgetExceeded` without reserving
+    /// anything if fewer than `amount` compute units remain.
+    pub fn reserve_compute_units(&self, amount: u64) -> Result<(), InstructionError> {
+        let mut compute_reserves = self.compute_reserves.borrow_mut();
+        let compute_reserve = compute_reserves
+            .last_mut()
+            .ok_or(InstructionError::CallDepth)?;
+        let mut compute_meter = self.compute_meter.borrow_mut();
+        if *compute_meter < amount {
+            return Err(InstructionError::ComputationalBudgetExceeded);
+        }
+        *compute_meter = compute_meter.saturating_sub(amount);
+        *compute_reserve = compute_reserve.saturating_add(amount);
+        Ok(())
+    }
+
+    /// Spend compute units reserved via `reserve_compute_units()` in the current frame
+    ///
+    /// If `amount` exceeds the reserve, the reserve is drained to zero and
+    /// `InstructionError::ComputationalBudgetExceeded` is returned.
+    pub fn consume_reserved(&self, amount: u64) -> Result<(), InstructionError> {
+        let mut compute_reserves = self.compute_reserves.borrow_mut();
+        let compute_reserve = compute_reserves
+            .last_mut()
+            .ok_or(InstructionError::CallDepth)?;
+        let exceeded = *compute_reserve < amount;
+        *compute_reserve = compute_reserve.saturating_sub(amount);
+        if exceeded {
+            return Err(InstructionError::ComputationalBudgetExceeded);
+        }
+        Ok(())
+    }
+
+    /// Compute units reserved and not yet spent in the current frame
+    pub fn get_reserved_compute_units(&self) -> u64 {
+        self.compute_reserves.borrow().last().copied().unwrap_or(0)
+    }
+
+    /// Charge for a heap of `heap_bytes`, to be called when a program declares its heap size
+    ///
+    /// Priced by the cost model, by default the first `MIN_HEAP_FRAME_BYTES` are free and every
+    /// further page of that size, or part thereof, costs `heap_cost` compute units. Fails with
+    /// `InstructionError::InvalidArgument` without charging anything if `heap_bytes` exceeds
+    /// `MAX_HEAP_FRAME_BYTES`.
+    pub fn charge_heap_cost(&self, heap_bytes: u32) -> Result<(), InstructionError> {
+        if heap_bytes > MAX_HEAP_FRAME_BYTES {
+            ic_msg!(
+                self,
+                "Heap size {} exceeds the maximum of {}",
+                heap_bytes,
+                MAX_HEAP_FRAME_BYTES,
+            );
+            return Err(InstructionError::InvalidArgument);
+        }
+        self.try_consume(self.cost_model.heap_cost(heap_bytes))
+            .map(|_remaining| ())
+    }
+
+    /// Charge for a memory operation, e.g. `sol_memcpy_` or `sol_memset_`, over `bytes` bytes
+    ///
+    /// Priced by the cost model, by default one compute unit per `cpi_bytes_per_unit` bytes,
+    /// but at least `mem_op_base_cost`, even for zero length operations.
+    pub fn charge_memory_op(&self, bytes: u64) -> Result<(), InstructionError> {
+        self.try_consume(self.cost_model.mem_op_cost(bytes))
+            .map(|_remaining| ())
+    }
+
+    /// Snapshot the compute meter, to be restored via `restore_compute_meter()`
+    pub fn checkpoint_compute_meter(&self) -> u64 {
+        self.get_remaining_compute_units()
+    }
+
+    /// Restore the compute meter to a value taken by `checkpoint_compute_meter()`
+    ///
+    /// Used to undo the charges of a speculative instruction, e.g. for fee simulation.
+    /// The invocation stack is not affected, `process_instruction()` always pops the
+    /// frames it pushed, even on failure. Fails with `InstructionError::InvalidArgument` if
+    /// `value` exceeds what the meter was ever set to, including legitimate raises.
+    pub fn restore_compute_meter(&self, value: u64) -> Result<(), InstructionError> {
+        if value > self.compute_meter_ceiling.get() {
+            return Err(InstructionError::InvalidArgument);
+        }
+        *self.compute_meter.borrow_mut() = value;
+        Ok(())
+    }
+
+    /// Set the remaining compute units, e.g. to grant compute to a privileged stage
+    ///
+    /// Fails with `InstructionError::InvalidArgument` if `new_remaining` exceeds the compute
+    /// unit limit of the budget, unless allowed via `set_allow_budget_increase()`.
+    pub fn set_compute_unit_limit(&self, new_remaining: u64) -> Result<(), InstructionError> {
+        if new_remaining > self.compute_budget.compute_unit_limit && !self.allow_budget_increase {
+            ic_msg!(
+                self,
+                "Compute unit limit of {} exceeds the budget of {}",
+                new_remaining,
+                self.compute_budget.compute_unit_limit,
+            );
+            return Err(InstructionError::InvalidArgument);
+        }
+        *self.compute_meter.borrow_mut() = new_remaining;
+        self.compute_meter_ceiling
+            .set(self.compute_meter_ceiling.get().max(new_remaining));
+        Ok(())
+    }
+
+    /// Allow `set_compute_unit_limit()` to exceed the compute unit limit of the budget
+    pub fn set_allow_budget_increase(&mut self, allow_budget_increase: bool) {
+        self.allow_budget_increase = allow_budget_increase;
+    }
+
+    /// Grant 1000 compute units per `lamports_per_1000_cu` lamports of `priority_fee`
+    ///
+    /// The remaining compute units are raised to at most the priority boost cap, which
+    /// defaults to `MAX_COMPUTE_UNIT_LIMIT`, see `set_priority_boost_cap()`. Nothing is granted
+    /// if `lamports_per_1000_cu` is zero. Never applied implicitly, so execution stays
+    /// deterministic unless the caller opts in. Returns the compute units granted.
+    pub fn apply_priority_boost(&mut self, priority_fee: u64, lamports_per_1000_cu: u64) -> u64 {
+        let boost = priority_fee
+            .checked_div(lamports_per_1000_cu)
+            .unwrap_or(0)
+            .saturating_mul(1000);
+        let mut compute_meter = self.compute_meter.borrow_mut();
+        let boosted = compute_meter
+            .saturating_add(boost)
+            .min(self.priority_boost_cap)
+            .max(*compute_meter);
+        let granted = boosted.saturating_sub(*compute_meter);
+        *compute_meter = boosted;
+        self.compute_meter_ceiling
+            .set(self.compute_meter_ceiling.get().max(boosted));
+        granted
+    }
+
+    /// Set the remaining compute units which `apply_priority_boost()` never exceeds
+    pub fn set_priority_boost_cap(&mut self, priority_boost_cap: u64) {
+        self.priority_boost_cap = priority_boost_cap;
+    }
+
+    /// Charge `contention_level * cost_multiplier` compute units for write contention
+    ///
+    /// The counterpart of `apply_priority_boost()`, which only ever deducts compute. The
+    /// contention level is measured by the caller, e.g. the scheduler. The product saturates,
+    /// so a high contention drains the meter like any other charge via `try_consume()`.
+    pub fn apply_contention_adjustment(
+        &mut self,
+        contention_level: u64,
+        cost_multiplier: u64,
+    ) -> Result<(), InstructionError> {
+        self.try_consume(contention_level.saturating_mul(cost_multiplier))
+            .map(|_remaining| ())
+    }
+
+    /// Set compute units
+    ///
+    /// Only use for tests and benchmarks
+    pub fn mock_set_remaining(&self, remaining: u64) {
+        *self.compute_meter.borrow_mut() = remaining;
+        self.compute_meter_ceiling
+            .set(self.compute_meter_ceiling.get().max(remaining));
+    }
+
+    /// Whether the compute meter does not exceed the compute units it was ever set to
+    pub fn compute_meter_invariant_ok(&self) -> bool {
+        self.get_remaining_compute_units() <= self.compute_meter_ceiling.get()
+    }
+
+    /// Get this invocation's compute budget
+    pub fn get_compute_budget(&self) -> &SVMTransactionExecutionBudget {
+        &self.compute_budget
+    }
+
+    /// Get this invocation's compute cost
+    pub fn get_execution_cost(&self) -> &SVMTransactionExecutionCost {
+        &self.execution_cost
+    }
+
+    /// Get the cost model pricing the operations charged by the helpers of this context
+    pub fn get_cost_model(&self) -> &dyn CostModel {
+        self.cost_model.as_ref()
+    }
+
+    /// Replace the cost model, e.g. to simulate a different fee schedule
+    ///
+    /// Only affects the compute units charged by this context, not those charged by
+    /// programs and loaders themselves.
+    pub fn set_cost_model(&mut self, cost_model: Arc<dyn CostModel>) {
+        self.cost_model = cost_model;
     }
 
     /// Get the current feature set.
     pub fn get_feature_set(&self) -> &SVMFeatureSet {
-        self.environment_config.feature_set
+        self.environment_config.active_features()
     }
 
     pub fn is_stake_raise_minimum_delegation_to_1_sol_active(&self) -> bool {
-        self.environment_config
-            .feature_set
+        self.get_feature_set()
             .stake_raise_minimum_delegation_to_1_sol
     }
 
     pub fn is_deprecate_legacy_vote_ixs_active(&self) -> bool {
-        self.environment_config
-            .feature_set
-            .deprecate_legacy_vote_ixs
+        self.get_feature_set().deprecate_legacy_vote_ixs
     }
 
     /// Get cached sysvars
@@ -662,6 +2668,38 @@ impl<'a> InvokeContext<'a> {
         self.environment_config.sysvar_cache
     }
 
+    /// Get the cached `Clock` sysvar, charged like the `sol_get_clock_sysvar` syscall
+    pub fn get_sysvar_clock(&self) -> Result<Arc<Clock>, InstructionError> {
+        self.get_sysvar_with_cost("sol_get_clock_sysvar", SysvarCache::get_clock)
+    }
+
+    /// Get the cached `Rent` sysvar, charged like the `sol_get_rent_sysvar` syscall
+    pub fn get_sysvar_rent(&self) -> Result<Arc<Rent>, InstructionError> {
+        self.get_sysvar_with_cost("sol_get_rent_sysvar", SysvarCache::get_rent)
+    }
+
+    /// Get the cached `EpochSchedule` sysvar, charged like the `sol_get_epoch_schedule_sysvar`
+    /// syscall
+    pub fn get_sysvar_epoch_schedule(&self) -> Result<Arc<EpochSchedule>, InstructionError> {
+        self.get_sysvar_with_cost(
+            "sol_get_epoch_schedule_sysvar",
+            SysvarCache::get_epoch_schedule,
+        )
+    }
+
+    /// Charge the sysvar base cost plus the size of the sysvar, then read it from the cache
+    ///
+    /// Returns `InstructionError::UnsupportedSysvar` if the sysvar is not cached,
+    /// in which case the compute units are still consumed.
+    fn get_sysvar_with_cost<T>(
+        &self,
+        syscall_name: &'static str,
+        get_sysvar: impl FnOnce(&SysvarCache) -> Result<Arc<T>, InstructionError>,
+    ) -> Result<Arc<T>, InstructionError> {
+        self.consume_for_syscall(syscall_name, self.cost_model.sysvar_cost(size_of::<T>()))?;
+        get_sysvar(self.get_sysvar_cache())
+    }
+
     /// Get cached epoch total stake.
     pub fn get_epoch_stake(&self) -> u64 {
         self.environment_config
@@ -676,6 +2714,10 @@ impl<'a> InvokeContext<'a> {
             .get_epoch_stake_for_vote_account(pubkey)
     }
 
+    /// Whether `pubkey` is a precompile, as determined by the runtime through the
+    /// `InvokeContextCallback`, which takes the active features into account
+    ///
+    /// Precompile instructions are executed via `process_precompile()`.
     pub fn is_precompile(&self, pubkey: &Pubkey) -> bool {
         self.environment_config
             .epoch_stake_callback
@@ -724,43 +2766,574 @@ impl<'a> InvokeContext<'a> {
             .ok_or(InstructionError::CallDepth)
     }
 
+    /// Number of frames on the invocation stack which have a syscall context set
+    ///
+    /// Unlike `get_stack_height()`, frames of builtins and frames which did not reach
+    /// the VM are not counted.
+    pub fn syscall_depth(&self) -> usize {
+        self.syscall_context
+            .iter()
+            .filter(|syscall_context| syscall_context.is_some())
+            .count()
+    }
+
+    /// Push a frame of `program_id` with a syscall context, without executing anything
+    ///
+    /// Lets syscalls be tested in isolation. `accounts` are the indices in the transaction
+    /// of the instruction accounts, which are all writable and not signers. The heap of the
+    /// syscall context has the size of the compute budget. Undo via `pop_mock_frame()`.
+    #[cfg(feature = "dev-context-only-utils")]
+    pub fn push_mock_frame(
+        &mut self,
+        program_id: Pubkey,
+        accounts: &[IndexOfAccount],
+    ) -> Result<(), InstructionError> {
+        let program_index = self
+            .transaction_context
+            .find_index_of_account(&program_id)
+            .ok_or(InstructionError::MissingAccount)?;
+        let instruction_accounts = accounts
+            .iter()
+            .enumerate()
+            .map(|(instruction_account_index, index_in_transaction)| {
+                let index_in_callee = accounts
+                    .iter()
+                    .position(|index| index == index_in_transaction)
+                    .unwrap_or(instruction_account_index);
+                InstructionAccount {
+                    index_in_transaction: *index_in_transaction,
+                    index_in_caller: *index_in_transaction,
+                    index_in_callee: index_in_callee as IndexOfAccount,
+                    is_signer: false,
+                    is_writable: true,
+                }
+            })
+            .collect::<Vec<_>>();
+        self.transaction_context
+            .get_next_instruction_context()?
+            .configure(&[program_index], &instruction_accounts, &[]);
+        self.push()?;
+        self.set_syscall_context(SyscallContext {
+            allocator: BpfAllocator::new(u64::from(self.compute_budget.heap_size)),
+            accounts_metadata: Vec::new(),
+            trace_log: Vec::new(),
+        })
+    }
+
+    /// Reproducible randomness for syscalls in tests, seeded when the context is built
+    #[cfg(feature = "dev-context-only-utils")]
+    pub fn deterministic_rng(&mut self) -> &mut DeterministicRng {
+        &mut self.rng
+    }
+
+    /// Pop a frame pushed by `push_mock_frame()`
+    #[cfg(feature = "dev-context-only-utils")]
+    pub fn pop_mock_frame(&mut self) -> Result<(), InstructionError> {
+        self.pop()
+    }
+
+    /// Record the compute units charged by a syscall and count its invocation
+    ///
+    /// Called from the syscall dispatch path after the syscall charged `cu`. The helpers of
+    /// the context which are charged like a syscall, e.g. `log()`, record themselves.
+    pub fn record_syscall_cost(&self, name: &'static str, cu: u64) {
+        self.syscall_costs.borrow_mut().record(name, cu);
+    }
+
+    /// Return the compute units charged by each syscall and its number of invocations so far
+    pub fn get_syscall_costs(&self) -> Ref<'_, SyscallCosts> {
+        self.syscall_costs.borrow()
+    }
+
+    /// Charge `cost` for the syscall `name` and record it via `record_syscall_cost()`
+    fn consume_for_syscall(&self, name: &'static str, cost: u64) -> Result<(), InstructionError> {
+        self.try_consume(cost)?;
+        self.record_syscall_cost(name, cost);
+        Ok(())
+    }
+
+    /// Get the heap usage of this instruction's SyscallContext, if any
+    pub fn get_syscall_context_allocator_stats(&self) -> Option<AllocatorStats> {
+        self.get_syscall_context()
+            .ok()
+            .map(|syscall_context| syscall_context.allocator.stats())
+    }
+
+    /// Describe the state of the invocation in a human readable form, e.g. for bug reports
+    ///
+    /// Lists the stack height, the remaining compute units, the program ids of the frames on
+    /// the invocation stack, whether the current frame has a syscall context and the accounts
+    /// of the transaction which are loaded in the program cache.
+    pub fn dump_state(&self) -> String {
+        let stack_height = self.get_stack_height();
+        let remaining = self
+            .compute_meter
+            .try_borrow()
+            .map(|compute_meter| compute_meter.to_string())
+            .unwrap_or_else(|_| "unknown".to_string());
+        let current_program_id = self
+            .current_program_id()
+            .map(|program_id| program_id.to_string())
+            .unwrap_or_else(|_| "none".to_string());
+        let invocation_stack = (0..stack_height)
+            .map(|nesting_level| {
+                self.transaction_context
+                    .get_instruction_context_at_nesting_level(nesting_level)
+                    .and_then(|instruction_context| {
+                        instruction_context.get_last_program_key(self.transaction_context)
+                    })
+                    .map(|program_id| program_id.to_string())
+                    .unwrap_or_else(|_| "unknown".to_string())
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let syscall_context = match self.syscall_context.last() {
+            Some(Some(_)) => "set",
+            Some(None) => "not set",
+            None => "none",
+        };
+        let loaded_programs = (0..self.transaction_context.get_number_of_accounts())
+            .filter_map(|index_in_transaction| {
+                self.transaction_context
+                    .get_key_of_account_at_index(index_in_transaction)
+                    .ok()
+            })
+            .filter(|program_id| self.program_cache_for_tx_batch.find(program_id).is_some())
+            .map(|program_id| program_id.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!(
+            "stack height: {stack_height}\n\
+             remaining compute units: {remaining} of {}\n\
+             current program: {current_program_id}\n\
+             invocation stack: [{invocation_stack}]\n\
+             syscall context: {syscall_context}\n\
+             loaded programs: [{loaded_programs}]",
+            self.compute_budget.compute_unit_limit,
+        )
+    }
+
     /// Return a references to traces
     pub fn get_traces(&self) -> &Vec<Vec<[u64; 12]>> {
         &self.traces
     }
-}
 
-#[macro_export]
-macro_rules! with_mock_invoke_context_with_feature_set {
-    (
-        $invoke_context:ident,
-        $transaction_context:ident,
-        $feature_set:ident,
-        $transaction_accounts:expr $(,)?
-    ) => {
-        use {
-            solana_log_collector::LogCollector,
-            solana_svm_callback::InvokeContextCallback,
-            $crate::{
-                __private::{Hash, ReadableAccount, Rent, TransactionContext},
-                execution_budget::{SVMTransactionExecutionBudget, SVMTransactionExecutionCost},
-                invoke_context::{EnvironmentConfig, InvokeContext},
-                loaded_programs::ProgramCacheForTxBatch,
-                sysvar_cache::SysvarCache,
-            },
-        };
+    /// Number of frames recorded in `get_traces()`
+    pub fn instruction_trace_length(&self) -> usize {
+        self.traces.len()
+    }
 
-        struct MockInvokeContextCallback {}
-        impl InvokeContextCallback for MockInvokeContextCallback {}
+    /// Number of trace rows recorded for each frame in `get_traces()`
+    ///
+    /// Unlike the lengths of the traces themselves, this includes the rows
+    /// forwarded to the trace sink, if one is set.
+    pub fn trace_frame_lengths(&self) -> &[usize] {
+        &self.trace_frame_lengths
+    }
 
-        let compute_budget = SVMTransactionExecutionBudget::default();
-        let mut $transaction_context = TransactionContext::new(
-            $transaction_accounts,
-            Rent::default(),
-            compute_budget.max_instruction_stack_depth,
-            compute_budget.max_instruction_trace_length,
-        );
-        let mut sysvar_cache = SysvarCache::default();
+    /// Compare the traces recorded so far against a recorded reference, row by row
+    ///
+    /// Returns the first divergence in the order of execution. Frames whose rows were
+    /// forwarded to a trace sink or filtered out compare as empty.
+    pub fn verify_trace(&self, recorded: &[Vec<[u64; 12]>]) -> Result<(), TraceMismatch> {
+        for (frame, (expected_trace, actual_trace)) in recorded.iter().zip(&self.traces).enumerate()
+        {
+            for (row, (expected_row, actual_row)) in
+                expected_trace.iter().zip(actual_trace).enumerate()
+            {
+                if let Some((register, (expected, actual))) = expected_row
+                    .iter()
+                    .zip(actual_row)
+                    .enumerate()
+                    .find(|(_register, (expected, actual))| expected != actual)
+                {
+                    return Err(TraceMismatch::Register {
+                        frame,
+                        row,
+                        register,
+                        expected: *expected,
+                        actual: *actual,
+                    });
+                }
+            }
+            if expected_trace.len() != actual_trace.len() {
+                return Err(TraceMismatch::RowCount {
+                    frame,
+                    expected: expected_trace.len(),
+                    actual: actual_trace.len(),
+                });
+            }
+        }
+        if recorded.len() != self.traces.len() {
+            return Err(TraceMismatch::FrameCount {
+                expected: recorded.len(),
+                actual: self.traces.len(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Decode the trace of the frame at index `frame` of `get_traces()`
+    ///
+    /// Returns an empty trace if there is no such frame.
+    pub fn decode_trace(&self, frame: usize) -> Vec<TraceFrame> {
+        self.traces
+            .get(frame)
+            .map(|trace| trace.iter().copied().map(TraceFrame::from).collect())
+            .unwrap_or_default()
+    }
+
+    /// Enable or disable recording traces, enabled by default
+    ///
+    /// When disabled, neither the rows reported by the VM nor the per frame traces are kept,
+    /// which saves one `traces` entry per popped frame that had a syscall context plus the
+    /// row buffer of frames executed with instruction tracing enabled in the VM config.
+    pub fn set_tracing_enabled(&mut self, tracing_enabled: bool) {
+        self.tracing_enabled = tracing_enabled;
+    }
+
+    /// Bound the total number of trace rows kept in memory, or lift the bound if `None`
+    ///
+    /// Unbounded by default. Once the bound is reached, further rows are dropped and
+    /// `traces_were_truncated()` returns true. Rows forwarded to a trace sink are not counted.
+    pub fn set_max_trace_rows(&mut self, max_trace_rows: Option<usize>) {
+        self.max_trace_rows = max_trace_rows;
+    }
+
+    /// Whether trace rows were dropped because of the bound set via `set_max_trace_rows()`
+    pub fn traces_were_truncated(&self) -> bool {
+        self.traces_truncated
+    }
+
+    /// Forward trace rows to the given sink instead of keeping them in memory
+    ///
+    /// `get_traces()` then only contains an empty trace per frame.
+    pub fn set_trace_sink(&mut self, trace_sink: Option<Box<dyn TraceSink>>) {
+        self.trace_sink = trace_sink;
+    }
+
+    /// Execute programs with the given backend instead of the built-in VM, or with the
+    /// built-in VM again if `None`
+    ///
+    /// The backend takes over the whole execution of a frame after the program was found in
+    /// the program cache, including the program logs and resetting the return data.
+    pub fn set_vm_backend(&mut self, vm_backend: Option<Rc<dyn VmBackend>>) {
+        self.vm_backend = vm_backend;
+    }
+
+    /// Only keep the traces of the given program, or of all programs if `None`
+    ///
+    /// Frames of other programs are recorded as empty traces.
+    pub fn set_trace_filter(&mut self, trace_filter: Option<Pubkey>) {
+        self.trace_filter = trace_filter;
+    }
+
+    /// Enable or disable measuring the execution time of builtin programs, enabled by default
+    ///
+    /// The measurements are accumulated in `timings.execute_us`. Programs executed by a loader
+    /// are measured by that loader instead and are not affected.
+    pub fn set_measure_execute_time(&mut self, measure_execute_time: bool) {
+        self.measure_execute_time = measure_execute_time;
+    }
+
+    /// Set the hook which is called with the amount and the remaining compute units afterwards
+    /// whenever compute units are consumed, via `ContextObject::consume()` or `try_consume()`
+    ///
+    /// The hook runs while the compute meter is borrowed, so it must not consume compute
+    /// units or access the compute meter of this context in any other way.
+    pub fn set_consume_hook(&mut self, consume_hook: Option<Box<dyn FnMut(u64, u64)>>) {
+        *self.consume_hook.get_mut() = consume_hook;
+    }
+
+    /// Set the hook which is called with the new stack height whenever a frame is pushed
+    ///
+    /// It is also called when `push()` fails, in which case the `on_pop` hook
+    /// is called right after it with the unchanged stack height.
+    pub fn set_on_push(&mut self, on_push: Option<Box<dyn FnMut(usize)>>) {
+        self.on_push = on_push;
+    }
+
+    /// Set the hook which is called with the new stack height whenever a frame is popped
+    pub fn set_on_pop(&mut self, on_pop: Option<Box<dyn FnMut(usize)>>) {
+        self.on_pop = on_pop;
+    }
+
+    /// Register a pending operation and return its promise id
+    ///
+    /// The operation is driven outside of the context, e.g. by a worker thread, which settles
+    /// it via `resolve_promise()` or `fail_promise()`. The context itself stays synchronous.
+    pub fn register_promise(&mut self) -> u64 {
+        let promise_id = self.next_promise_id;
+        self.next_promise_id = self.next_promise_id.saturating_add(1);
+        self.pending_promises
+            .insert(promise_id, PromiseState::Pending);
+        promise_id
+    }
+
+    /// Settle a pending promise with the result data of its operation
+    pub fn resolve_promise(
+        &mut self,
+        promise_id: u64,
+        data: Vec<u8>,
+    ) -> Result<(), InstructionError> {
+        self.settle_promise(promise_id, PromiseState::Resolved(data))
+    }
+
+    /// Settle a pending promise with the error of its operation
+    pub fn fail_promise(
+        &mut self,
+        promise_id: u64,
+        err: InstructionError,
+    ) -> Result<(), InstructionError> {
+        self.settle_promise(promise_id, PromiseState::Failed(err))
+    }
+
+    fn settle_promise(
+        &mut self,
+        promise_id: u64,
+        promise_state: PromiseState,
+    ) -> Result<(), InstructionError> {
+        match self.pending_promises.get_mut(&promise_id) {
+            Some(state @ PromiseState::Pending) => {
+                *state = promise_state;
+                Ok(())
+            }
+            _ => Err(InstructionError::InvalidArgument),
+        }
+    }
+
+    /// Poll a promise: `None` while pending, the data once resolved, the error once failed
+    ///
+    /// Fails with `InstructionError::InvalidArgument` if `promise_id` was never registered.
+    pub fn poll_promise(&self, promise_id: u64) -> Result<Option<&[u8]>, InstructionError> {
+        match self.pending_promises.get(&promise_id) {
+            Some(PromiseState::Pending) => Ok(None),
+            Some(PromiseState::Resolved(data)) => Ok(Some(data)),
+            Some(PromiseState::Failed(err)) => Err(err.clone()),
+            None => Err(InstructionError::InvalidArgument),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvokeContextBuilderError {
+    MissingTransactionContext,
+    MissingProgramCache,
+    MissingEnvironmentConfig,
+    MissingComputeBudget,
+    MissingExecutionCost,
+}
+impl fmt::Display for InvokeContextBuilderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let field = match self {
+            Self::MissingTransactionContext => "transaction_context",
+            Self::MissingProgramCache => "program_cache",
+            Self::MissingEnvironmentConfig => "environment_config",
+            Self::MissingComputeBudget => "compute_budget",
+            Self::MissingExecutionCost => "execution_cost",
+        };
+        write!(f, "InvokeContextBuilder is missing the {field}")
+    }
+}
+
+/// Builder for [InvokeContext]
+///
+/// The transaction context, program cache, environment config, compute budget and
+/// execution cost are required, everything else falls back to the same defaults
+/// as `InvokeContext::new()`.
+#[derive(Default)]
+pub struct InvokeContextBuilder<'a> {
+    transaction_context: Option<&'a mut TransactionContext>,
+    program_cache_for_tx_batch: Option<&'a mut ProgramCacheForTxBatch>,
+    environment_config: Option<EnvironmentConfig<'a>>,
+    log_collector: Option<Rc<RefCell<LogCollector>>>,
+    compute_budget: Option<SVMTransactionExecutionBudget>,
+    execution_cost: Option<SVMTransactionExecutionCost>,
+    cost_model: Option<Arc<dyn CostModel>>,
+    compute_meter: Option<SharedComputeMeter>,
+    trace_filter: Option<Pubkey>,
+    max_invoke_stack_height: Option<usize>,
+    #[cfg(feature = "dev-context-only-utils")]
+    rng_seed: Option<u64>,
+}
+
+impl<'a> InvokeContextBuilder<'a> {
+    pub fn transaction_context(mut self, transaction_context: &'a mut TransactionContext) -> Self {
+        self.transaction_context = Some(transaction_context);
+        self
+    }
+
+    pub fn program_cache(
+        mut self,
+        program_cache_for_tx_batch: &'a mut ProgramCacheForTxBatch,
+    ) -> Self {
+        self.program_cache_for_tx_batch = Some(program_cache_for_tx_batch);
+        self
+    }
+
+    pub fn environment_config(mut self, environment_config: EnvironmentConfig<'a>) -> Self {
+        self.environment_config = Some(environment_config);
+        self
+    }
+
+    pub fn log_collector(mut self, log_collector: Option<Rc<RefCell<LogCollector>>>) -> Self {
+        self.log_collector = log_collector;
+        self
+    }
+
+    pub fn compute_budget(mut self, compute_budget: SVMTransactionExecutionBudget) -> Self {
+        self.compute_budget = Some(compute_budget);
+        self
+    }
+
+    pub fn execution_cost(mut self, execution_cost: SVMTransactionExecutionCost) -> Self {
+        self.execution_cost = Some(execution_cost);
+        self
+    }
+
+    /// See `InvokeContext::set_cost_model()`
+    pub fn cost_model(mut self, cost_model: Arc<dyn CostModel>) -> Self {
+        self.cost_model = Some(cost_model);
+        self
+    }
+
+    /// See `InvokeContext::new_with_shared_meter()`
+    pub fn shared_compute_meter(mut self, compute_meter: SharedComputeMeter) -> Self {
+        self.compute_meter = Some(compute_meter);
+        self
+    }
+
+    /// See `InvokeContext::set_trace_filter()`
+    pub fn trace_filter(mut self, trace_filter: Pubkey) -> Self {
+        self.trace_filter = Some(trace_filter);
+        self
+    }
+
+    /// See `InvokeContext::set_max_invoke_stack_height()`
+    pub fn max_invoke_stack_height(mut self, max_invoke_stack_height: usize) -> Self {
+        self.max_invoke_stack_height = Some(max_invoke_stack_height);
+        self
+    }
+
+    /// Seed of `InvokeContext::deterministic_rng()`, defaults to zero
+    #[cfg(feature = "dev-context-only-utils")]
+    pub fn rng_seed(mut self, rng_seed: u64) -> Self {
+        self.rng_seed = Some(rng_seed);
+        self
+    }
+
+    pub fn build(self) -> Result<InvokeContext<'a>, InvokeContextBuilderError> {
+        let compute_budget = self
+            .compute_budget
+            .ok_or(InvokeContextBuilderError::MissingComputeBudget)?;
+        let execution_cost = self
+            .execution_cost
+            .ok_or(InvokeContextBuilderError::MissingExecutionCost)?;
+        let compute_meter_shared = self.compute_meter.is_some();
+        let SharedComputeMeter {
+            remaining: compute_meter,
+            ceiling: compute_meter_ceiling,
+        } = self
+            .compute_meter
+            .unwrap_or_else(|| SharedComputeMeter::new(compute_budget.compute_unit_limit));
+        Ok(InvokeContext {
+            transaction_context: self
+                .transaction_context
+                .ok_or(InvokeContextBuilderError::MissingTransactionContext)?,
+            program_cache_for_tx_batch: self
+                .program_cache_for_tx_batch
+                .ok_or(InvokeContextBuilderError::MissingProgramCache)?,
+            environment_config: self
+                .environment_config
+                .ok_or(InvokeContextBuilderError::MissingEnvironmentConfig)?,
+            log_collector: self.log_collector,
+            compute_budget,
+            execution_cost,
+            cost_model: self.cost_model.unwrap_or_else(|| Arc::new(execution_cost)),
+            compute_meter,
+            compute_meter_ceiling,
+            compute_meter_withheld: Rc::new(Cell::new(0)),
+            compute_meter_shared,
+            allow_budget_increase: false,
+            priority_boost_cap: u64::from(MAX_COMPUTE_UNIT_LIMIT),
+            execute_time: None,
+            measure_execute_time: true,
+            timings: ExecuteDetailsTimings::default(),
+            syscall_context: Vec::new(),
+            traces: Vec::new(),
+            trace_frame_lengths: Vec::new(),
+            recorded_trace_rows: 0,
+            max_trace_rows: None,
+            traces_truncated: false,
+            syscall_costs: RefCell::new(SyscallCosts::default()),
+            compute_breakdown: RefCell::new(BTreeMap::new()),
+            max_invoke_stack_height: self
+                .max_invoke_stack_height
+                .unwrap_or(compute_budget.max_instruction_stack_depth),
+            reentrancy_allowlist: HashSet::new(),
+            trace_filter: self.trace_filter,
+            tracing_enabled: true,
+            trace_sink: None,
+            vm_backend: None,
+            soft_limit_fraction: None,
+            soft_limit_warned: Cell::new(false),
+            frame_entry_compute_meter: Vec::new(),
+            frame_nested_compute_units: Vec::new(),
+            peak_frame_consumption: 0,
+            consumption_timeline_start: None,
+            consumption_timeline: Vec::new(),
+            frame_cu_cap: None,
+            execution_interrupt: Cell::new(None),
+            execution_deadline: None,
+            time_source: Rc::new(Instant::now),
+            compute_reserves: RefCell::new(Vec::new()),
+            track_accounts_touched: false,
+            accounts_touched: Vec::new(),
+            writable_accounts_touched: Vec::new(),
+            #[cfg(feature = "dev-context-only-utils")]
+            rng: DeterministicRng::new(self.rng_seed.unwrap_or(0)),
+            consume_hook: RefCell::new(None),
+            on_push: None,
+            on_pop: None,
+            pending_promises: HashMap::new(),
+            next_promise_id: 0,
+        })
+    }
+}
+
+#[macro_export]
+macro_rules! with_mock_invoke_context_with_feature_set {
+    (
+        $invoke_context:ident,
+        $transaction_context:ident,
+        $feature_set:ident,
+        $transaction_accounts:expr
+        $(, $sysvar:expr)* $(,)?
+    ) => {
+        use {
+            solana_log_collector::LogCollector,
+            solana_svm_callback::InvokeContextCallback,
+            $crate::{
+                __private::{Hash, ReadableAccount, Rent, TransactionContext},
+                execution_budget::{SVMTransactionExecutionBudget, SVMTransactionExecutionCost},
+                invoke_context::{EnvironmentConfig, InvokeContext},
+                loaded_programs::ProgramCacheForTxBatch,
+                sysvar_cache::SysvarCache,
+            },
+        };
+
+        struct MockInvokeContextCallback {}
+        impl InvokeContextCallback for MockInvokeContextCallback {}
+
+        let compute_budget = SVMTransactionExecutionBudget::default();
+        let mut $transaction_context = TransactionContext::new(
+            $transaction_accounts,
+            Rent::default(),
+            compute_budget.max_instruction_stack_depth,
+            compute_budget.max_instruction_trace_length,
+        );
+        let mut sysvar_cache = SysvarCache::default();
+        $(sysvar_cache.set_sysvar_for_tests(&$sysvar);)*
         sysvar_cache.fill_missing_entries(|pubkey, callback| {
             for index in 0..$transaction_context.get_number_of_accounts() {
                 if $transaction_context
@@ -815,6 +3388,31 @@ macro_rules! with_mock_invoke_context {
     };
 }
 
+/// Like `with_mock_invoke_context!` but with the sysvar cache prefilled with the given
+/// `Clock` and `Rent`, in addition to the sysvar accounts in `$transaction_accounts`
+#[cfg(feature = "dev-context-only-utils")]
+#[macro_export]
+macro_rules! with_mock_invoke_context_and_sysvars {
+    (
+        $invoke_context:ident,
+        $transaction_context:ident,
+        $transaction_accounts:expr,
+        $clock:expr,
+        $rent:expr $(,)?
+    ) => {
+        use $crate::with_mock_invoke_context_with_feature_set;
+        let feature_set = &solana_svm_feature_set::SVMFeatureSet::default();
+        with_mock_invoke_context_with_feature_set!(
+            $invoke_context,
+            $transaction_context,
+            feature_set,
+            $transaction_accounts,
+            $clock,
+            $rent
+        )
+    };
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn mock_process_instruction_with_feature_set<
     F: FnMut(&mut InvokeContext),
@@ -935,7 +3533,10 @@ mod tests {
         serde::{Deserialize, Serialize},
         solana_account::WritableAccount,
         solana_instruction::Instruction,
-        solana_rent::Rent,
+        solana_sbpf::{
+            elf::Executable,
+            program::{BuiltinProgram, FunctionRegistry},
+        },
         test_case::test_case,
     };
 
@@ -955,6 +3556,12 @@ mod tests {
         Resize {
             new_len: u64,
         },
+        Sleep {
+            duration_ms: u64,
+        },
+        ConsumeInSteps {
+            steps: u64,
+        },
     }
 
     const MOCK_BUILTIN_COMPUTE_UNIT_COST: u64 = 1;
@@ -1051,6 +3658,14 @@ mod tests {
                     MockInstruction::Resize { new_len } => instruction_context
                         .try_borrow_instruction_account(transaction_context, 0)?
                         .set_data(vec![0; new_len as usize])?,
+                    MockInstruction::Sleep { duration_ms } => {
+                        std::thread::sleep(Duration::from_millis(duration_ms))
+                    }
+                    MockInstruction::ConsumeInSteps { steps } => {
+                        for _ in 0..steps {
+                            invoke_context.try_consume(1)?;
+                        }
+                    }
                 }
             } else {
                 return Err(InstructionError::InvalidInstructionData);
@@ -1359,4 +3974,3328 @@ mod tests {
             resize_delta
         );
     }
+
+    #[test]
+    fn test_get_remaining_and_consumed_compute_units() {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let compute_unit_limit = invoke_context.get_compute_budget().compute_unit_limit;
+        assert_eq!(
+            invoke_context.get_remaining_compute_units(),
+            compute_unit_limit
+        );
+        assert_eq!(invoke_context.get_consumed_compute_units(), 0);
+
+        invoke_context
+            .transaction_context
+            .get_next_instruction_context()
+            .unwrap()
+            .configure(&[0], &[], &[]);
+        invoke_context.push().unwrap();
+        invoke_context.consume_checked(10).unwrap();
+        assert_eq!(
+            invoke_context.get_remaining_compute_units(),
+            compute_unit_limit.saturating_sub(10)
+        );
+        assert_eq!(invoke_context.get_consumed_compute_units(), 10);
+        invoke_context.pop().unwrap();
+        assert_eq!(invoke_context.get_consumed_compute_units(), 10);
+    }
+
+    #[test]
+    fn test_try_consume() {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        invoke_context.mock_set_remaining(10);
+
+        assert_eq!(invoke_context.try_consume(0), Ok(10));
+        assert_eq!(invoke_context.try_consume(4), Ok(6));
+        assert_eq!(invoke_context.try_consume(6), Ok(0));
+        assert_eq!(invoke_context.try_consume(0), Ok(0));
+        assert_eq!(
+            invoke_context.try_consume(1),
+            Err(InstructionError::ComputationalBudgetExceeded)
+        );
+        assert_eq!(invoke_context.get_remaining_compute_units(), 0);
+
+        invoke_context.mock_set_remaining(5);
+        assert_eq!(
+            invoke_context.try_consume(u64::MAX),
+            Err(InstructionError::ComputationalBudgetExceeded)
+        );
+        assert_eq!(invoke_context.get_remaining_compute_units(), 0);
+    }
+
+    #[test]
+    fn test_checkpoint_and_restore_compute_meter() {
+        let callee_program_id = solana_pubkey::new_rand();
+        let mut program_account = AccountSharedData::new(1, 1, &native_loader::id());
+        program_account.set_executable(true);
+        let transaction_accounts = vec![
+            (
+                solana_pubkey::new_rand(),
+                AccountSharedData::new(42, 1, &callee_program_id),
+            ),
+            (
+                solana_pubkey::new_rand(),
+                AccountSharedData::new(84, 1, &solana_pubkey::new_rand()),
+            ),
+            (
+                solana_pubkey::new_rand(),
+                AccountSharedData::new(168, 1, &solana_pubkey::new_rand()),
+            ),
+            (callee_program_id, program_account),
+        ];
+        let instruction_accounts = (0..4)
+            .map(|instruction_account_index| InstructionAccount {
+                index_in_transaction: instruction_account_index,
+                index_in_caller: instruction_account_index,
+                index_in_callee: instruction_account_index,
+                is_signer: false,
+                is_writable: false,
+            })
+            .collect::<Vec<_>>();
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let mut program_cache_for_tx_batch = ProgramCacheForTxBatch::default();
+        program_cache_for_tx_batch.replenish(
+            callee_program_id,
+            Arc::new(ProgramCacheEntry::new_builtin(0, 1, MockBuiltin::vm)),
+        );
+        invoke_context.program_cache_for_tx_batch = &mut program_cache_for_tx_batch;
+
+        let checkpoint = invoke_context.checkpoint_compute_meter();
+        let instruction_data = bincode::serialize(&MockInstruction::ConsumeComputeUnits {
+            compute_units_to_consume: 10,
+            desired_result: Err(InstructionError::GenericError),
+        })
+        .unwrap();
+        let result = invoke_context.process_instruction(
+            &instruction_data,
+            &instruction_accounts,
+            &[3],
+            &mut 0,
+            &mut ExecuteTimings::default(),
+        );
+        assert_eq!(result, Err(InstructionError::GenericError));
+        assert_eq!(invoke_context.get_stack_height(), 0);
+        assert!(invoke_context.get_remaining_compute_units() < checkpoint);
+
+        invoke_context.restore_compute_meter(checkpoint).unwrap();
+        assert_eq!(invoke_context.get_remaining_compute_units(), checkpoint);
+        assert_eq!(
+            invoke_context.restore_compute_meter(
+                invoke_context
+                    .get_compute_budget()
+                    .compute_unit_limit
+                    .saturating_add(1)
+            ),
+            Err(InstructionError::InvalidArgument)
+        );
+        assert_eq!(invoke_context.get_remaining_compute_units(), checkpoint);
+
+        // A checkpoint taken after a priority boost above the limit can be restored
+        invoke_context.set_priority_boost_cap(u64::MAX);
+        assert_eq!(invoke_context.apply_priority_boost(1_000, 1), 1_000_000);
+        let checkpoint = invoke_context.checkpoint_compute_meter();
+        assert!(checkpoint > invoke_context.get_compute_budget().compute_unit_limit);
+        invoke_context.try_consume(10).unwrap();
+        invoke_context.restore_compute_meter(checkpoint).unwrap();
+        assert_eq!(invoke_context.get_remaining_compute_units(), checkpoint);
+        assert_eq!(
+            invoke_context.restore_compute_meter(checkpoint.saturating_add(1)),
+            Err(InstructionError::InvalidArgument)
+        );
+    }
+
+    #[test]
+    fn test_record_syscall_cost() {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        assert_eq!(*invoke_context.get_syscall_costs(), SyscallCosts::default());
+
+        invoke_context.record_syscall_cost("sol_log_", 100);
+        invoke_context.record_syscall_cost("sol_sha256", 85);
+        invoke_context.record_syscall_cost("sol_log_", 100);
+        let syscall_costs = invoke_context.get_syscall_costs().clone();
+        assert_eq!(
+            syscall_costs
+                .compute_units
+                .iter()
+                .map(|(name, cu)| (*name, *cu))
+                .collect::<Vec<_>>(),
+            vec![("sol_log_", 200), ("sol_sha256", 85)],
+        );
+        assert_eq!(
+            syscall_costs
+                .counts
+                .iter()
+                .map(|(name, count)| (*name, *count))
+                .collect::<Vec<_>>(),
+            vec![("sol_log_", 2), ("sol_sha256", 1)],
+        );
+
+        // The helpers charged like a syscall record what they charged
+        invoke_context.mock_set_remaining(1_000);
+        invoke_context.log("hello").unwrap();
+        let log_cost = invoke_context.get_cost_model().log_cost("hello".len());
+        assert_eq!(
+            invoke_context
+                .get_syscall_costs()
+                .compute_units
+                .get("sol_log_"),
+            Some(&200u64.saturating_add(log_cost))
+        );
+        assert_eq!(
+            invoke_context.get_syscall_costs().counts.get("sol_log_"),
+            Some(&3)
+        );
+
+        // Failed charges are not recorded
+        invoke_context.mock_set_remaining(0);
+        assert_eq!(
+            invoke_context.log_data(&[b"data"]),
+            Err(InstructionError::ComputationalBudgetExceeded)
+        );
+        assert!(!invoke_context
+            .get_syscall_costs()
+            .counts
+            .contains_key("sol_log_data"));
+
+        let mut batch = syscall_costs.clone();
+        batch.accumulate(&syscall_costs);
+        assert_eq!(batch.compute_units.get("sol_sha256"), Some(&170));
+        assert_eq!(batch.counts.get("sol_log_"), Some(&4));
+    }
+
+    #[test]
+    fn test_native_invoke_pops_on_error() {
+        let callee_program_id = solana_pubkey::new_rand();
+        let owned_account = AccountSharedData::new(42, 1, &callee_program_id);
+        let not_owned_account = AccountSharedData::new(84, 1, &solana_pubkey::new_rand());
+        let readonly_account = AccountSharedData::new(168, 1, &solana_pubkey::new_rand());
+        let loader_account = AccountSharedData::new(0, 1, &native_loader::id());
+        let mut program_account = AccountSharedData::new(1, 1, &native_loader::id());
+        program_account.set_executable(true);
+        let transaction_accounts = vec![
+            (solana_pubkey::new_rand(), owned_account),
+            (solana_pubkey::new_rand(), not_owned_account),
+            (solana_pubkey::new_rand(), readonly_account),
+            (callee_program_id, program_account),
+            (solana_pubkey::new_rand(), loader_account),
+        ];
+        let instruction_accounts = (0..4)
+            .map(|instruction_account_index| InstructionAccount {
+                index_in_transaction: instruction_account_index,
+                index_in_caller: instruction_account_index,
+                index_in_callee: instruction_account_index,
+                is_signer: false,
+                is_writable: instruction_account_index < 2,
+            })
+            .collect::<Vec<_>>();
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let mut program_cache_for_tx_batch = ProgramCacheForTxBatch::default();
+        program_cache_for_tx_batch.replenish(
+            callee_program_id,
+            Arc::new(ProgramCacheEntry::new_builtin(0, 1, MockBuiltin::vm)),
+        );
+        invoke_context.program_cache_for_tx_batch = &mut program_cache_for_tx_batch;
+        invoke_context
+            .transaction_context
+            .get_next_instruction_context()
+            .unwrap()
+            .configure(&[4], &instruction_accounts, &[]);
+        invoke_context.push().unwrap();
+        let owned_key = *invoke_context
+            .transaction_context
+            .get_key_of_account_at_index(0)
+            .unwrap();
+        let not_owned_key = *invoke_context
+            .transaction_context
+            .get_key_of_account_at_index(1)
+            .unwrap();
+
+        // Failing callee
+        let metas = vec![
+            AccountMeta::new(owned_key, false),
+            AccountMeta::new(not_owned_key, false),
+        ];
+        let inner_instruction =
+            Instruction::new_with_bincode(callee_program_id, &MockInstruction::NoopFail, metas);
+        assert_eq!(
+            invoke_context.native_invoke(inner_instruction.into(), &[]),
+            Err(InstructionError::GenericError)
+        );
+        assert_eq!(invoke_context.get_stack_height(), 1);
+
+        // Signer privilege escalation
+        let metas = vec![
+            AccountMeta::new(owned_key, true),
+            AccountMeta::new(not_owned_key, false),
+        ];
+        let inner_instruction = Instruction::new_with_bincode(
+            callee_program_id,
+            &MockInstruction::NoopSuccess,
+            metas.clone(),
+        );
+        assert_eq!(
+            invoke_context.native_invoke(inner_instruction.clone().into(), &[]),
+            Err(InstructionError::PrivilegeEscalation)
+        );
+        assert_eq!(invoke_context.get_stack_height(), 1);
+        assert_eq!(
+            invoke_context.native_invoke(inner_instruction.into(), &[owned_key]),
+            Ok(())
+        );
+        assert_eq!(invoke_context.get_stack_height(), 1);
+        invoke_context.pop().unwrap();
+    }
+
+    #[test]
+    fn test_max_invoke_stack_height() {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        assert_eq!(
+            invoke_context.max_invoke_stack_height(),
+            SVMTransactionExecutionBudget::default().max_instruction_stack_depth
+        );
+
+        invoke_context.set_max_invoke_stack_height(1);
+        assert_eq!(invoke_context.max_invoke_stack_height(), 1);
+        for expected_result in [Ok(()), Err(InstructionError::CallDepth)] {
+            invoke_context
+                .transaction_context
+                .get_next_instruction_context()
+                .unwrap()
+                .configure(&[0], &[], &[]);
+            assert_eq!(invoke_context.push(), expected_result);
+        }
+        assert_eq!(invoke_context.get_stack_height(), 1);
+        invoke_context.pop().unwrap();
+    }
+
+    #[test]
+    fn test_reentrancy_allowlist() {
+        let router_program_id = solana_pubkey::new_rand();
+        let transaction_accounts = vec![
+            (router_program_id, AccountSharedData::default()),
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+        ];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let push_program = |invoke_context: &mut InvokeContext, program_index: IndexOfAccount| {
+            invoke_context
+                .transaction_context
+                .get_next_instruction_context()
+                .unwrap()
+                .configure(&[program_index], &[], &[]);
+            invoke_context.push()
+        };
+
+        push_program(&mut invoke_context, 0).unwrap();
+        push_program(&mut invoke_context, 1).unwrap();
+        assert_eq!(
+            push_program(&mut invoke_context, 0),
+            Err(InstructionError::ReentrancyNotAllowed)
+        );
+
+        invoke_context.set_reentrancy_allowlist(HashSet::from([router_program_id]));
+        push_program(&mut invoke_context, 0).unwrap();
+        assert_eq!(invoke_context.get_stack_height(), 3);
+    }
+
+    #[test]
+    fn test_get_parent_instruction_context() {
+        let caller_program_id = solana_pubkey::new_rand();
+        let callee_program_id = solana_pubkey::new_rand();
+        let transaction_accounts = vec![
+            (caller_program_id, AccountSharedData::default()),
+            (callee_program_id, AccountSharedData::default()),
+        ];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        assert_eq!(
+            invoke_context.get_parent_instruction_context().err(),
+            Some(InstructionError::CallDepth)
+        );
+
+        invoke_context
+            .transaction_context
+            .get_next_instruction_context()
+            .unwrap()
+            .configure(&[0], &[], &[]);
+        invoke_context.push().unwrap();
+        assert_eq!(
+            invoke_context.get_parent_instruction_context().err(),
+            Some(InstructionError::CallDepth)
+        );
+
+        invoke_context
+            .transaction_context
+            .get_next_instruction_context()
+            .unwrap()
+            .configure(&[1], &[], &[]);
+        invoke_context.push().unwrap();
+        let parent_instruction_context = invoke_context.get_parent_instruction_context().unwrap();
+        assert_eq!(
+            parent_instruction_context
+                .get_last_program_key(invoke_context.transaction_context)
+                .unwrap(),
+            &caller_program_id
+        );
+        assert_eq!(
+            invoke_context
+                .transaction_context
+                .get_current_instruction_context()
+                .unwrap()
+                .get_last_program_key(invoke_context.transaction_context)
+                .unwrap(),
+            &callee_program_id
+        );
+    }
+
+    #[test]
+    fn test_prepare_instruction_dry_run() {
+        let callee_program_id = solana_pubkey::new_rand();
+        let mut program_account = AccountSharedData::new(1, 1, &native_loader::id());
+        program_account.set_executable(true);
+        let transaction_accounts = vec![
+            (
+                solana_pubkey::new_rand(),
+                AccountSharedData::new(42, 1, &callee_program_id),
+            ),
+            (
+                solana_pubkey::new_rand(),
+                AccountSharedData::new(84, 1, &solana_pubkey::new_rand()),
+            ),
+            (callee_program_id, program_account),
+        ];
+        let instruction_accounts = (0..3)
+            .map(|instruction_account_index| InstructionAccount {
+                index_in_transaction: instruction_account_index,
+                index_in_caller: instruction_account_index,
+                index_in_callee: instruction_account_index,
+                is_signer: false,
+                is_writable: instruction_account_index == 0,
+            })
+            .collect::<Vec<_>>();
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        invoke_context
+            .transaction_context
+            .get_next_instruction_context()
+            .unwrap()
+            .configure(&[2], &instruction_accounts, &[]);
+        invoke_context.push().unwrap();
+        let writable_key = *invoke_context
+            .transaction_context
+            .get_key_of_account_at_index(0)
+            .unwrap();
+        let readonly_key = *invoke_context
+            .transaction_context
+            .get_key_of_account_at_index(1)
+            .unwrap();
+        let logged = |invoke_context: &InvokeContext| {
+            invoke_context
+                .get_log_collector()
+                .unwrap()
+                .borrow()
+                .get_recorded_content()
+                .len()
+        };
+
+        for (metas, expected_result) in [
+            (
+                vec![
+                    AccountMeta::new(writable_key, false),
+                    AccountMeta::new_readonly(readonly_key, false),
+                ],
+                Ok(()),
+            ),
+            (
+                vec![AccountMeta::new(readonly_key, false)],
+                Err(InstructionError::PrivilegeEscalation),
+            ),
+            (
+                vec![AccountMeta::new_readonly(writable_key, true)],
+                Err(InstructionError::PrivilegeEscalation),
+            ),
+        ] {
+            let instruction =
+                StableInstruction::from(Instruction::new_with_bytes(callee_program_id, &[], metas));
+            let logged_before = logged(&invoke_context);
+            assert_eq!(
+                invoke_context.prepare_instruction_dry_run(&instruction, &[]),
+                expected_result
+            );
+            assert_eq!(logged(&invoke_context), logged_before);
+            assert_eq!(
+                invoke_context
+                    .prepare_instruction(&instruction, &[])
+                    .map(|_| ()),
+                expected_result
+            );
+            assert_eq!(
+                logged(&invoke_context) > logged_before,
+                expected_result.is_err()
+            );
+        }
+        assert_eq!(invoke_context.get_stack_height(), 1);
+    }
+
+    #[test]
+    fn test_privilege_escalation_diagnostic() {
+        let callee_program_id = solana_pubkey::new_rand();
+        let mut program_account = AccountSharedData::new(1, 1, &native_loader::id());
+        program_account.set_executable(true);
+        let readonly_key = solana_pubkey::new_rand();
+        let transaction_accounts = vec![
+            (
+                readonly_key,
+                AccountSharedData::new(42, 1, &callee_program_id),
+            ),
+            (callee_program_id, program_account),
+        ];
+        let instruction_accounts = (0..2)
+            .map(|instruction_account_index| InstructionAccount {
+                index_in_transaction: instruction_account_index,
+                index_in_caller: instruction_account_index,
+                index_in_callee: instruction_account_index,
+                is_signer: false,
+                is_writable: false,
+            })
+            .collect::<Vec<_>>();
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        invoke_context
+            .transaction_context
+            .get_next_instruction_context()
+            .unwrap()
+            .configure(&[1], &instruction_accounts, &[]);
+        invoke_context.push().unwrap();
+
+        let instruction = StableInstruction::from(Instruction::new_with_bytes(
+            callee_program_id,
+            &[],
+            vec![AccountMeta::new(readonly_key, false)],
+        ));
+        assert_eq!(
+            invoke_context.prepare_instruction(&instruction, &[]),
+            Err(InstructionError::PrivilegeEscalation)
+        );
+        let log_collector = invoke_context.get_log_collector().unwrap();
+        assert_eq!(
+            log_collector.borrow().get_recorded_content(),
+            &[format!(
+                "Privilege escalation of account {readonly_key} (index 0 in caller, 0 in \
+                 callee): is_writable is false in caller but true in callee"
+            )]
+        );
+    }
+
+    #[test_case(None, Ok(()), 11; "NoTimeout")]
+    #[test_case(Some(Duration::from_millis(5)), Err(InstructionError::ProgramFailedToComplete), 6; "Timeout")]
+    fn test_execution_timeout(
+        execution_timeout: Option<Duration>,
+        expected_result: Result<(), InstructionError>,
+        expected_compute_units_consumed: u64,
+    ) {
+        let program_key = Pubkey::new_unique();
+        let mut program_account = AccountSharedData::new(1, 1, &native_loader::id());
+        program_account.set_executable(true);
+        let transaction_accounts = vec![
+            (
+                Pubkey::new_unique(),
+                AccountSharedData::new(42, 1, &program_key),
+            ),
+            (
+                Pubkey::new_unique(),
+                AccountSharedData::new(84, 1, &Pubkey::new_unique()),
+            ),
+            (program_key, program_account),
+        ];
+        let instruction_accounts = (0..2)
+            .map(|instruction_account_index| InstructionAccount {
+                index_in_transaction: instruction_account_index,
+                index_in_caller: instruction_account_index,
+                index_in_callee: instruction_account_index,
+                is_signer: false,
+                is_writable: false,
+            })
+            .collect::<Vec<_>>();
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let mut program_cache_for_tx_batch = ProgramCacheForTxBatch::default();
+        program_cache_for_tx_batch.replenish(
+            program_key,
+            Arc::new(ProgramCacheEntry::new_builtin(0, 0, MockBuiltin::vm)),
+        );
+        invoke_context.program_cache_for_tx_batch = &mut program_cache_for_tx_batch;
+        invoke_context.environment_config.execution_timeout = execution_timeout;
+
+        // A mock clock advancing by one millisecond per compute unit consumed
+        let start = Instant::now();
+        let elapsed = Rc::new(Cell::new(Duration::ZERO));
+        invoke_context.set_consume_hook(Some(Box::new({
+            let elapsed = Rc::clone(&elapsed);
+            move |amount, _remaining| {
+                elapsed.set(elapsed.get().saturating_add(Duration::from_millis(amount)));
+            }
+        })));
+        invoke_context.set_time_source(Rc::new(move || start.checked_add(elapsed.get()).unwrap()));
+
+        let instruction_data =
+            bincode::serialize(&MockInstruction::ConsumeInSteps { steps: 10 }).unwrap();
+        let pre_remaining_units = invoke_context.get_remaining_compute_units();
+        let mut compute_units_consumed = 0;
+        let result = invoke_context.process_instruction(
+            &instruction_data,
+            &instruction_accounts,
+            &[2],
+            &mut compute_units_consumed,
+            &mut ExecuteTimings::default(),
+        );
+        assert_eq!(result, expected_result);
+        assert_eq!(
+            invoke_context.execution_interrupt(),
+            execution_timeout.map(|_| ExecutionInterrupt::Timeout)
+        );
+        // The program is stopped at the first charge past the deadline, not run to completion
+        assert_eq!(compute_units_consumed, expected_compute_units_consumed);
+        assert_eq!(
+            invoke_context.get_remaining_compute_units(),
+            pre_remaining_units.saturating_sub(expected_compute_units_consumed)
+        );
+        assert_eq!(invoke_context.get_stack_height(), 0);
+    }
+
+    #[test]
+    fn test_trace_filter() {
+        let traced_program_id = solana_pubkey::new_rand();
+        let transaction_accounts = vec![
+            (traced_program_id, AccountSharedData::default()),
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+        ];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        invoke_context.set_trace_filter(Some(traced_program_id));
+
+        for (program_index, trace_row) in [(0, [1; 12]), (1, [2; 12])] {
+            invoke_context
+                .transaction_context
+                .get_next_instruction_context()
+                .unwrap()
+                .configure(&[program_index], &[], &[]);
+            invoke_context.push().unwrap();
+            invoke_context
+                .set_syscall_context(SyscallContext {
+                    allocator: BpfAllocator::new(0),
+                    accounts_metadata: Vec::new(),
+                    trace_log: vec![trace_row],
+                })
+                .unwrap();
+            invoke_context.pop().unwrap();
+        }
+        assert_eq!(
+            invoke_context.get_traces(),
+            &vec![vec![[1; 12]], Vec::new()]
+        );
+    }
+
+    #[test]
+    fn test_tracing_disabled() {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        invoke_context.set_tracing_enabled(false);
+
+        invoke_context
+            .transaction_context
+            .get_next_instruction_context()
+            .unwrap()
+            .configure(&[0], &[], &[]);
+        invoke_context.push().unwrap();
+        invoke_context
+            .set_syscall_context(SyscallContext {
+                allocator: BpfAllocator::new(0),
+                accounts_metadata: Vec::new(),
+                trace_log: Vec::new(),
+            })
+            .unwrap();
+        invoke_context.trace([1; 12]);
+        assert!(invoke_context
+            .get_syscall_context()
+            .unwrap()
+            .trace_log
+            .is_empty());
+        invoke_context.pop().unwrap();
+        assert!(invoke_context.get_traces().is_empty());
+    }
+
+    #[test]
+    fn test_trace_sink() {
+        struct MockTraceSink(Rc<RefCell<Vec<(usize, [u64; 12])>>>);
+        impl TraceSink for MockTraceSink {
+            fn record(&mut self, frame: usize, row: [u64; 12]) {
+                self.0.borrow_mut().push((frame, row));
+            }
+        }
+
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let recorded = Rc::new(RefCell::new(Vec::new()));
+        invoke_context.set_trace_sink(Some(Box::new(MockTraceSink(recorded.clone()))));
+
+        for trace_log in [vec![[1; 12], [2; 12]], vec![[3; 12]]] {
+            invoke_context
+                .transaction_context
+                .get_next_instruction_context()
+                .unwrap()
+                .configure(&[0], &[], &[]);
+            invoke_context.push().unwrap();
+            invoke_context
+                .set_syscall_context(SyscallContext {
+                    allocator: BpfAllocator::new(0),
+                    accounts_metadata: Vec::new(),
+                    trace_log,
+                })
+                .unwrap();
+            invoke_context.pop().unwrap();
+        }
+        assert_eq!(
+            *recorded.borrow(),
+            vec![(0, [1; 12]), (0, [2; 12]), (1, [3; 12])]
+        );
+        assert_eq!(invoke_context.get_traces(), &vec![Vec::new(), Vec::new()]);
+    }
+
+    #[test]
+    fn test_reset() {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        invoke_context
+            .transaction_context
+            .get_next_instruction_context()
+            .unwrap()
+            .configure(&[0], &[], &[]);
+        invoke_context.push().unwrap();
+        invoke_context
+            .set_syscall_context(SyscallContext {
+                allocator: BpfAllocator::new(0),
+                accounts_metadata: Vec::new(),
+                trace_log: vec![[1; 12]],
+            })
+            .unwrap();
+        invoke_context.consume_checked(10).unwrap();
+        invoke_context.pop().unwrap();
+        invoke_context.record_syscall_cost("sol_log_", 100);
+        let promise_id = invoke_context.register_promise();
+        let traces_capacity = invoke_context.get_traces().capacity();
+
+        invoke_context.reset();
+        assert_eq!(
+            invoke_context.get_remaining_compute_units(),
+            invoke_context.get_compute_budget().compute_unit_limit
+        );
+        assert!(invoke_context.syscall_context.is_empty());
+        assert!(invoke_context.get_traces().is_empty());
+        assert_eq!(invoke_context.get_traces().capacity(), traces_capacity);
+        assert_eq!(*invoke_context.get_syscall_costs(), SyscallCosts::default());
+        assert_eq!(invoke_context.timings, ExecuteDetailsTimings::default());
+        assert_eq!(
+            invoke_context.poll_promise(promise_id),
+            Err(InstructionError::InvalidArgument)
+        );
+        assert_eq!(
+            invoke_context.register_promise(),
+            promise_id.saturating_add(1)
+        );
+    }
+
+    #[test]
+    fn test_soft_limit_warning() {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let compute_unit_limit = invoke_context.get_compute_budget().compute_unit_limit;
+        invoke_context.set_soft_limit_fraction(Some(0.5));
+        let warnings = |invoke_context: &InvokeContext| {
+            invoke_context
+                .get_log_collector()
+                .unwrap()
+                .borrow()
+                .get_recorded_content()
+                .iter()
+                .filter(|message| message.starts_with("Compute unit soft limit reached"))
+                .count()
+        };
+
+        invoke_context
+            .transaction_context
+            .get_next_instruction_context()
+            .unwrap()
+            .configure(&[0], &[], &[]);
+        invoke_context.push().unwrap();
+        invoke_context
+            .consume_checked(compute_unit_limit.saturating_div(2).saturating_sub(1))
+            .unwrap();
+        assert_eq!(warnings(&invoke_context), 0);
+        invoke_context.consume_checked(1).unwrap();
+        assert_eq!(warnings(&invoke_context), 1);
+        invoke_context.consume_checked(1).unwrap();
+        assert_eq!(warnings(&invoke_context), 1);
+
+        // Nested frame warns again
+        invoke_context
+            .transaction_context
+            .get_next_instruction_context()
+            .unwrap()
+            .configure(&[0], &[], &[]);
+        invoke_context.push().unwrap();
+        invoke_context.consume_checked(1).unwrap();
+        assert_eq!(warnings(&invoke_context), 2);
+    }
+
+    #[test]
+    fn test_syscall_context_allocator_stats() {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        assert_eq!(invoke_context.get_syscall_context_allocator_stats(), None);
+
+        invoke_context
+            .transaction_context
+            .get_next_instruction_context()
+            .unwrap()
+            .configure(&[0], &[], &[]);
+        invoke_context.push().unwrap();
+        assert_eq!(invoke_context.get_syscall_context_allocator_stats(), None);
+        invoke_context
+            .set_syscall_context(SyscallContext {
+                allocator: BpfAllocator::new(64),
+                accounts_metadata: Vec::new(),
+                trace_log: Vec::new(),
+            })
+            .unwrap();
+        let allocator = &mut invoke_context.get_syscall_context_mut().unwrap().allocator;
+        allocator
+            .alloc(Layout::from_size_align(3, 1).unwrap())
+            .unwrap();
+        allocator
+            .alloc(Layout::from_size_align(8, 8).unwrap())
+            .unwrap();
+        assert!(allocator
+            .alloc(Layout::from_size_align(64, 8).unwrap())
+            .is_err());
+        assert_eq!(
+            invoke_context.get_syscall_context_allocator_stats(),
+            Some(AllocatorStats {
+                bytes_allocated: 16,
+                peak_usage: 16,
+                allocation_count: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn test_syscall_context_out_of_heap() {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        invoke_context
+            .transaction_context
+            .get_next_instruction_context()
+            .unwrap()
+            .configure(&[0], &[], &[]);
+        invoke_context.push().unwrap();
+        // A `BpfAllocator` of length n fails every allocation beyond n bytes
+        invoke_context
+            .set_syscall_context(SyscallContext {
+                allocator: BpfAllocator::new(8),
+                accounts_metadata: Vec::new(),
+                trace_log: Vec::new(),
+            })
+            .unwrap();
+        let allocator = &mut invoke_context.get_syscall_context_mut().unwrap().allocator;
+        let layout = Layout::from_size_align(8, 8).unwrap();
+        assert!(allocator.alloc(layout).is_ok());
+        assert_eq!(allocator.alloc(layout), Err(AllocErr));
+        invoke_context.pop().unwrap();
+        assert!(invoke_context.syscall_context.is_empty());
+    }
+
+    #[test]
+    fn test_get_syscall_context() {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        assert_eq!(
+            invoke_context.get_syscall_context().err(),
+            Some(InstructionError::CallDepth)
+        );
+        assert_eq!(
+            invoke_context.get_syscall_context_mut().err(),
+            Some(InstructionError::CallDepth)
+        );
+
+        invoke_context
+            .transaction_context
+            .get_next_instruction_context()
+            .unwrap()
+            .configure(&[0], &[], &[]);
+        invoke_context.push().unwrap();
+        assert_eq!(
+            invoke_context.get_syscall_context().err(),
+            Some(InstructionError::CallDepth)
+        );
+        invoke_context
+            .set_syscall_context(SyscallContext {
+                allocator: BpfAllocator::new(0),
+                accounts_metadata: Vec::new(),
+                trace_log: Vec::new(),
+            })
+            .unwrap();
+        invoke_context
+            .get_syscall_context_mut()
+            .unwrap()
+            .trace_log
+            .push([1; 12]);
+        assert_eq!(
+            invoke_context.get_syscall_context().unwrap().trace_log,
+            vec![[1; 12]]
+        );
+    }
+
+    #[test]
+    fn test_is_top_level() {
+        let transaction_accounts = vec![
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+        ];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        assert_eq!(invoke_context.get_stack_height(), 0);
+        assert!(!invoke_context.is_top_level());
+
+        for (program_index, expected_stack_height, expected_top_level) in
+            [(0, 1, true), (1, 2, false)]
+        {
+            invoke_context
+                .transaction_context
+                .get_next_instruction_context()
+                .unwrap()
+                .configure(&[program_index], &[], &[]);
+            invoke_context.push().unwrap();
+            assert_eq!(invoke_context.get_stack_height(), expected_stack_height);
+            assert_eq!(invoke_context.is_top_level(), expected_top_level);
+        }
+
+        invoke_context.pop().unwrap();
+        assert!(invoke_context.is_top_level());
+        invoke_context.pop().unwrap();
+        assert!(!invoke_context.is_top_level());
+    }
+
+    #[test]
+    fn test_push_and_pop_hooks() {
+        let transaction_accounts = vec![
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+        ];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let on_push_events = events.clone();
+        invoke_context.set_on_push(Some(Box::new(move |stack_height| {
+            on_push_events.borrow_mut().push(("push", stack_height));
+        })));
+        let on_pop_events = events.clone();
+        invoke_context.set_on_pop(Some(Box::new(move |stack_height| {
+            on_pop_events.borrow_mut().push(("pop", stack_height));
+        })));
+        let push_program = |invoke_context: &mut InvokeContext, program_index: IndexOfAccount| {
+            invoke_context
+                .transaction_context
+                .get_next_instruction_context()
+                .unwrap()
+                .configure(&[program_index], &[], &[]);
+            invoke_context.push()
+        };
+
+        push_program(&mut invoke_context, 0).unwrap();
+        push_program(&mut invoke_context, 1).unwrap();
+        assert_eq!(
+            push_program(&mut invoke_context, 0),
+            Err(InstructionError::ReentrancyNotAllowed)
+        );
+        invoke_context.pop().unwrap();
+        invoke_context.pop().unwrap();
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                ("push", 1),
+                ("push", 2),
+                ("push", 3),
+                ("pop", 2),
+                ("pop", 1),
+                ("pop", 0),
+            ]
+        );
+
+        // Failing to pop an empty stack does not call the hook
+        assert!(invoke_context.pop().is_err());
+        assert_eq!(events.borrow().len(), 6);
+    }
+
+    #[test]
+    fn test_unbalanced_pop_calls_hook() {
+        let transaction_accounts = vec![
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+        ];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let pops = Rc::new(RefCell::new(Vec::new()));
+        let on_pop_pops = pops.clone();
+        invoke_context.set_on_pop(Some(Box::new(move |stack_height| {
+            on_pop_pops.borrow_mut().push(stack_height);
+        })));
+        invoke_context.set_record_consumption_timeline(true);
+        let instruction_accounts = [InstructionAccount {
+            index_in_transaction: 1,
+            index_in_caller: 1,
+            index_in_callee: 0,
+            is_signer: false,
+            is_writable: true,
+        }];
+        invoke_context
+            .transaction_context
+            .get_next_instruction_context()
+            .unwrap()
+            .configure(&[0], &instruction_accounts, &[]);
+        invoke_context.push().unwrap();
+        invoke_context.try_consume(10).unwrap();
+        invoke_context
+            .transaction_context
+            .get_current_instruction_context()
+            .unwrap()
+            .try_borrow_instruction_account(invoke_context.transaction_context, 0)
+            .unwrap()
+            .checked_add_lamports(1)
+            .unwrap();
+
+        // The frame is gone despite the error, so the hook and the bookkeeping follow
+        assert_eq!(
+            invoke_context.pop(),
+            Err(InstructionError::UnbalancedInstruction)
+        );
+        assert_eq!(invoke_context.get_stack_height(), 0);
+        assert_eq!(*pops.borrow(), vec![0]);
+        assert_eq!(invoke_context.peak_frame_consumption(), 10);
+        assert_eq!(invoke_context.consumption_timeline(), vec![(1, 0), (0, 10)]);
+    }
+
+    #[test]
+    fn test_pop_with_usage() {
+        let transaction_accounts = vec![
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+        ];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        for program_index in 0..2 {
+            invoke_context
+                .transaction_context
+                .get_next_instruction_context()
+                .unwrap()
+                .configure(&[program_index], &[], &[]);
+            invoke_context.push().unwrap();
+            invoke_context.consume(100);
+        }
+
+        assert_eq!(invoke_context.pop_with_usage(), Ok(100));
+        invoke_context.consume(10);
+        assert_eq!(invoke_context.pop_with_usage(), Ok(210));
+        assert_eq!(
+            invoke_context.pop_with_usage(),
+            Err(InstructionError::CallDepth)
+        );
+    }
+
+    #[test]
+    fn test_invoke_context_builder() {
+        struct MockInvokeContextCallback {}
+        impl InvokeContextCallback for MockInvokeContextCallback {}
+
+        let compute_budget = SVMTransactionExecutionBudget::default();
+        let mut transaction_context = TransactionContext::new(
+            vec![(solana_pubkey::new_rand(), AccountSharedData::default())],
+            Rent::default(),
+            compute_budget.max_instruction_stack_depth,
+            compute_budget.max_instruction_trace_length,
+        );
+        let mut program_cache_for_tx_batch = ProgramCacheForTxBatch::default();
+        let feature_set = SVMFeatureSet::default();
+        let sysvar_cache = SysvarCache::default();
+        let environment_config = EnvironmentConfig::new(
+            Hash::default(),
+            0,
+            &MockInvokeContextCallback {},
+            &feature_set,
+            &sysvar_cache,
+        );
+        assert_eq!(
+            InvokeContextBuilder::default()
+                .compute_budget(compute_budget)
+                .build()
+                .err(),
+            Some(InvokeContextBuilderError::MissingTransactionContext)
+        );
+
+        let trace_filter = solana_pubkey::new_rand();
+        let invoke_context = InvokeContextBuilder::default()
+            .transaction_context(&mut transaction_context)
+            .program_cache(&mut program_cache_for_tx_batch)
+            .environment_config(environment_config)
+            .compute_budget(compute_budget)
+            .execution_cost(SVMTransactionExecutionCost::default())
+            .trace_filter(trace_filter)
+            .max_invoke_stack_height(2)
+            .build()
+            .unwrap();
+        assert!(invoke_context.get_log_collector().is_none());
+        assert_eq!(
+            invoke_context.get_remaining_compute_units(),
+            compute_budget.compute_unit_limit
+        );
+        assert_eq!(invoke_context.max_invoke_stack_height(), 2);
+        assert_eq!(invoke_context.trace_filter, Some(trace_filter));
+    }
+
+    #[test]
+    fn test_consume_with_label() {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        invoke_context.mock_set_remaining(100);
+        assert!(invoke_context.compute_breakdown().is_empty());
+
+        assert_eq!(invoke_context.consume_with_label(30, "verify"), Ok(()));
+        assert_eq!(invoke_context.consume_with_label(20, "serialize"), Ok(()));
+        assert_eq!(invoke_context.consume_with_label(10, "verify"), Ok(()));
+        assert_eq!(
+            invoke_context.consume_with_label(50, "serialize"),
+            Err(InstructionError::ComputationalBudgetExceeded)
+        );
+        assert_eq!(invoke_context.get_remaining_compute_units(), 0);
+        assert_eq!(
+            *invoke_context.compute_breakdown(),
+            BTreeMap::from([("serialize", 60), ("verify", 40)])
+        );
+
+        // The breakdown adds up to what was charged, even when the charge fails
+        invoke_context.reset();
+        invoke_context.mock_set_remaining(100);
+        invoke_context.set_frame_cu_cap(Some(10));
+        invoke_context
+            .transaction_context
+            .get_next_instruction_context()
+            .unwrap()
+            .configure(&[0], &[], &[]);
+        invoke_context.push().unwrap();
+        assert_eq!(
+            invoke_context.consume_with_label(30, "verify"),
+            Err(InstructionError::ComputationalBudgetExceeded)
+        );
+        assert_eq!(invoke_context.get_remaining_compute_units(), 70);
+        assert_eq!(
+            *invoke_context.compute_breakdown(),
+            BTreeMap::from([("verify", 30)])
+        );
+    }
+
+    #[test]
+    fn test_decode_trace() {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        invoke_context
+            .transaction_context
+            .get_next_instruction_context()
+            .unwrap()
+            .configure(&[0], &[], &[]);
+        invoke_context.push().unwrap();
+        invoke_context
+            .set_syscall_context(SyscallContext {
+                allocator: BpfAllocator::new(0),
+                accounts_metadata: Vec::new(),
+                trace_log: vec![[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]],
+            })
+            .unwrap();
+        invoke_context.pop().unwrap();
+
+        let trace = invoke_context.decode_trace(0);
+        assert_eq!(trace.len(), 1);
+        assert_eq!(trace[0].r0, 0);
+        assert_eq!(trace[0].r1, 1);
+        assert_eq!(trace[0].r10, 10);
+        assert_eq!(trace[0].pc, 11);
+        assert!(invoke_context.decode_trace(1).is_empty());
+    }
+
+    #[test]
+    fn test_writer_trace_sink() {
+        let rows = vec![(0, [1; 12]), (0, [2; 12]), (3, [u64::MAX; 12])];
+        let mut trace_sink = WriterTraceSink::new(Vec::new(), 2);
+        for (frame, row) in rows.iter().copied() {
+            trace_sink.record(frame, row);
+        }
+        let bytes = trace_sink.finish().unwrap();
+        assert_eq!(bytes.len(), rows.len().saturating_mul(TRACE_RECORD_SIZE));
+        assert_eq!(&bytes[..8], &0u64.to_le_bytes());
+        assert_eq!(&bytes[8..16], &1u64.to_le_bytes());
+        assert_eq!(
+            read_trace_rows(bytes.as_slice())
+                .collect::<io::Result<Vec<_>>>()
+                .unwrap(),
+            rows
+        );
+
+        let mut truncated_rows = read_trace_rows(&bytes[..bytes.len().saturating_sub(1)]);
+        assert_eq!(truncated_rows.next().unwrap().unwrap(), rows[0]);
+        assert_eq!(truncated_rows.next().unwrap().unwrap(), rows[1]);
+        assert_eq!(
+            truncated_rows.next().unwrap().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert!(truncated_rows.next().is_none());
+    }
+
+    #[test]
+    fn test_compressed_file_trace_sink() {
+        let rows = (0..100)
+            .map(|index| (index % 3, [index as u64; 12]))
+            .collect::<Vec<_>>();
+        let mut trace_sink = CompressedFileTraceSink::new(Vec::new(), 3, 10).unwrap();
+        for (frame, row) in rows.iter().copied() {
+            trace_sink.record(frame, row);
+        }
+        let bytes = trace_sink.finish().unwrap();
+        assert!(bytes.len() < rows.len().saturating_mul(TRACE_RECORD_SIZE));
+        assert_eq!(
+            read_compressed_trace_rows(bytes.as_slice())
+                .unwrap()
+                .collect::<io::Result<Vec<_>>>()
+                .unwrap(),
+            rows
+        );
+    }
+
+    #[test_case(false; "inactive")]
+    #[test_case(true; "active")]
+    fn test_active_features(is_active: bool) {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        let feature_set = &SVMFeatureSet {
+            deprecate_legacy_vote_ixs: is_active,
+            ..SVMFeatureSet::default()
+        };
+        with_mock_invoke_context_with_feature_set!(
+            invoke_context,
+            transaction_context,
+            feature_set,
+            transaction_accounts
+        );
+        assert_eq!(
+            invoke_context
+                .environment_config
+                .active_features()
+                .deprecate_legacy_vote_ixs,
+            is_active
+        );
+        assert_eq!(
+            invoke_context.is_deprecate_legacy_vote_ixs_active(),
+            is_active
+        );
+        assert!(!invoke_context.is_stake_raise_minimum_delegation_to_1_sol_active());
+    }
+
+    #[test]
+    fn test_get_sysvar_with_cost() {
+        let transaction_accounts = vec![(
+            sysvar::epoch_schedule::id(),
+            create_account_shared_data_for_test(&EpochSchedule::default()),
+        )];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let sysvar_base_cost = invoke_context.get_execution_cost().sysvar_base_cost;
+        invoke_context.mock_set_remaining(1_000);
+
+        assert_eq!(
+            *invoke_context.get_sysvar_epoch_schedule().unwrap(),
+            EpochSchedule::default()
+        );
+        let expected_remaining = 1_000u64
+            .saturating_sub(sysvar_base_cost)
+            .saturating_sub(size_of::<EpochSchedule>() as u64);
+        assert_eq!(
+            invoke_context.get_remaining_compute_units(),
+            expected_remaining
+        );
+
+        // Missing sysvars are charged as well
+        assert_eq!(
+            invoke_context.get_sysvar_clock().err(),
+            Some(InstructionError::UnsupportedSysvar)
+        );
+        assert_eq!(
+            invoke_context.get_remaining_compute_units(),
+            expected_remaining
+                .saturating_sub(sysvar_base_cost)
+                .saturating_sub(size_of::<Clock>() as u64)
+        );
+
+        invoke_context.mock_set_remaining(0);
+        assert_eq!(
+            invoke_context.get_sysvar_rent().err(),
+            Some(InstructionError::ComputationalBudgetExceeded)
+        );
+    }
+
+    #[test]
+    fn test_find_program_account_index() {
+        let transaction_accounts = vec![
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+        ];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        assert_eq!(
+            invoke_context.find_program_account_index(),
+            Err(InstructionError::CallDepth)
+        );
+
+        for program_index in [1, 0] {
+            invoke_context
+                .transaction_context
+                .get_next_instruction_context()
+                .unwrap()
+                .configure(&[program_index], &[], &[]);
+            invoke_context.push().unwrap();
+            assert_eq!(
+                invoke_context.find_program_account_index(),
+                Ok(program_index)
+            );
+        }
+    }
+
+    #[test]
+    fn test_frame_cu_cap() {
+        let transaction_accounts = vec![
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+        ];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        invoke_context.set_frame_cu_cap(Some(100));
+        assert_eq!(invoke_context.get_frame_consumed_compute_units(), 0);
+        assert!(invoke_context.consume_checked(1_000).is_ok());
+
+        for program_index in 0..2 {
+            invoke_context
+                .transaction_context
+                .get_next_instruction_context()
+                .unwrap()
+                .configure(&[program_index], &[], &[]);
+            invoke_context.push().unwrap();
+            assert!(invoke_context.try_consume(60).is_ok());
+        }
+        assert_eq!(invoke_context.get_frame_consumed_compute_units(), 60);
+        assert_eq!(invoke_context.execution_interrupt(), None);
+        assert_eq!(
+            invoke_context.try_consume(50),
+            Err(InstructionError::ComputationalBudgetExceeded)
+        );
+        assert_eq!(
+            invoke_context.execution_interrupt(),
+            Some(ExecutionInterrupt::FrameCuCapExceeded)
+        );
+        assert_eq!(invoke_context.pop_with_usage(), Ok(110));
+
+        // The consumption of the inner frame does not count against the outer frame
+        assert_eq!(invoke_context.get_frame_consumed_compute_units(), 170);
+        assert_eq!(
+            invoke_context.get_frame_exclusive_consumed_compute_units(),
+            60
+        );
+        assert!(invoke_context.try_consume(40).is_ok());
+        assert_ne!(invoke_context.get_remaining(), 0);
+
+        // Consumption by the VM stops it by reporting no compute units remaining
+        invoke_context.consume(1);
+        assert_eq!(invoke_context.get_remaining(), 0);
+        assert_ne!(invoke_context.get_remaining_compute_units(), 0);
+        assert_eq!(
+            invoke_context.try_consume(0),
+            Err(InstructionError::ComputationalBudgetExceeded)
+        );
+
+        invoke_context.set_frame_cu_cap(None);
+        assert!(invoke_context.try_consume(0).is_ok());
+        assert_eq!(
+            invoke_context.get_remaining(),
+            invoke_context.get_remaining_compute_units()
+        );
+        let log_collector = invoke_context.get_log_collector().unwrap();
+        assert_eq!(
+            log_collector.borrow().get_recorded_content(),
+            &["Frame compute unit cap of 100 exceeded".to_string()]
+        );
+    }
+
+    #[cfg(feature = "dev-context-only-utils")]
+    #[test]
+    fn test_with_mock_invoke_context_and_sysvars() {
+        let clock = Clock {
+            slot: 42,
+            unix_timestamp: 1_700_000_000,
+            ..Clock::default()
+        };
+        let rent = Rent {
+            lamports_per_byte_year: 1,
+            ..Rent::default()
+        };
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context_and_sysvars!(
+            invoke_context,
+            transaction_context,
+            transaction_accounts,
+            clock.clone(),
+            rent
+        );
+        assert_eq!(*invoke_context.get_sysvar_clock().unwrap(), clock);
+        assert_eq!(*invoke_context.get_sysvar_rent().unwrap(), rent);
+        assert_eq!(
+            invoke_context.get_sysvar_epoch_schedule().err(),
+            Some(InstructionError::UnsupportedSysvar)
+        );
+    }
+
+    #[test]
+    fn test_current_program_id() {
+        let caller_program_id = solana_pubkey::new_rand();
+        let callee_program_id = solana_pubkey::new_rand();
+        let transaction_accounts = vec![
+            (caller_program_id, AccountSharedData::default()),
+            (callee_program_id, AccountSharedData::default()),
+        ];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        assert_eq!(
+            invoke_context.current_program_id(),
+            Err(InstructionError::CallDepth)
+        );
+
+        for (program_index, expected_program_id) in [(0, caller_program_id), (1, callee_program_id)]
+        {
+            invoke_context
+                .transaction_context
+                .get_next_instruction_context()
+                .unwrap()
+                .configure(&[program_index], &[], &[]);
+            invoke_context.push().unwrap();
+            assert_eq!(
+                invoke_context.current_program_id(),
+                Ok(&expected_program_id)
+            );
+        }
+
+        invoke_context.pop().unwrap();
+        assert_eq!(invoke_context.current_program_id(), Ok(&caller_program_id));
+    }
+
+    #[test]
+    fn test_compute_meter_invariant() {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        assert!(invoke_context.compute_meter_invariant_ok());
+
+        // Exhaustion keeps the meter consistent
+        invoke_context.mock_set_remaining(0);
+        assert_eq!(
+            invoke_context.try_consume(1),
+            Err(InstructionError::ComputationalBudgetExceeded)
+        );
+        assert!(invoke_context.compute_meter_invariant_ok());
+
+        // Mocking the meter above the compute unit limit raises the ceiling
+        let above_limit = invoke_context
+            .get_compute_budget()
+            .compute_unit_limit
+            .saturating_add(1);
+        invoke_context.mock_set_remaining(above_limit);
+        assert!(invoke_context.compute_meter_invariant_ok());
+        assert_eq!(
+            invoke_context.try_consume(1),
+            Ok(above_limit.saturating_sub(1))
+        );
+
+        // Raising the meter behind the ceiling's back is a metering bug
+        *invoke_context.compute_meter.borrow_mut() = above_limit.saturating_add(1);
+        assert!(!invoke_context.compute_meter_invariant_ok());
+    }
+
+    #[test]
+    fn test_with_reduced_budget() {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        invoke_context.mock_set_remaining(100);
+
+        {
+            let _guard = invoke_context.with_reduced_budget(30);
+            assert_eq!(invoke_context.get_remaining_compute_units(), 30);
+            assert_eq!(invoke_context.try_consume(10), Ok(20));
+            {
+                // A budget above the remaining compute units withholds nothing
+                let _guard = invoke_context.with_reduced_budget(50);
+                assert_eq!(invoke_context.get_remaining_compute_units(), 20);
+            }
+            assert_eq!(
+                invoke_context.try_consume(50),
+                Err(InstructionError::ComputationalBudgetExceeded)
+            );
+        }
+        assert_eq!(invoke_context.get_remaining_compute_units(), 70);
+    }
+
+    #[test]
+    fn test_process_instruction_with_reduced_budget() {
+        let program_key = Pubkey::new_unique();
+        let mut program_account = AccountSharedData::new(1, 1, &native_loader::id());
+        program_account.set_executable(true);
+        let transaction_accounts = vec![
+            (
+                Pubkey::new_unique(),
+                AccountSharedData::new(42, 1, &program_key),
+            ),
+            (
+                Pubkey::new_unique(),
+                AccountSharedData::new(84, 1, &Pubkey::new_unique()),
+            ),
+            (program_key, program_account),
+        ];
+        let instruction_accounts = (0..2)
+            .map(|instruction_account_index| InstructionAccount {
+                index_in_transaction: instruction_account_index,
+                index_in_caller: instruction_account_index,
+                index_in_callee: instruction_account_index,
+                is_signer: false,
+                is_writable: false,
+            })
+            .collect::<Vec<_>>();
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let mut program_cache_for_tx_batch = ProgramCacheForTxBatch::default();
+        program_cache_for_tx_batch.replenish(
+            program_key,
+            Arc::new(ProgramCacheEntry::new_builtin(0, 0, MockBuiltin::vm)),
+        );
+        invoke_context.program_cache_for_tx_batch = &mut program_cache_for_tx_batch;
+        invoke_context.mock_set_remaining(1_000);
+
+        let instruction_data =
+            bincode::serialize(&MockInstruction::ConsumeInSteps { steps: 50 }).unwrap();
+        let guard = invoke_context.with_reduced_budget(20);
+        let mut compute_units_consumed = 0;
+        let result = invoke_context.process_instruction(
+            &instruction_data,
+            &instruction_accounts,
+            &[2],
+            &mut compute_units_consumed,
+            &mut ExecuteTimings::default(),
+        );
+        assert_eq!(result, Err(InstructionError::ComputationalBudgetExceeded));
+        assert_eq!(compute_units_consumed, 20);
+        assert_eq!(invoke_context.get_remaining_compute_units(), 0);
+        drop(guard);
+        assert_eq!(invoke_context.get_remaining_compute_units(), 980);
+    }
+
+    #[test]
+    fn test_reduced_budget_not_consumed() {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let compute_unit_limit = invoke_context.get_compute_budget().compute_unit_limit;
+        invoke_context.set_frame_cu_cap(Some(100));
+        invoke_context
+            .transaction_context
+            .get_next_instruction_context()
+            .unwrap()
+            .configure(&[0], &[], &[]);
+        invoke_context.push().unwrap();
+        let guard = invoke_context.with_reduced_budget(50);
+        assert_eq!(invoke_context.get_frame_consumed_compute_units(), 0);
+        assert_eq!(invoke_context.try_consume(10), Ok(40));
+        assert_eq!(invoke_context.get_frame_consumed_compute_units(), 10);
+        assert_eq!(invoke_context.execution_interrupt(), None);
+        drop(guard);
+        assert_eq!(invoke_context.pop_with_usage(), Ok(10));
+
+        // A guard outliving a reset does not overfill the refilled meter
+        let guard = invoke_context.with_reduced_budget(50);
+        invoke_context.reset();
+        drop(guard);
+        assert_eq!(
+            invoke_context.get_remaining_compute_units(),
+            compute_unit_limit
+        );
+        assert!(invoke_context.compute_meter_invariant_ok());
+        assert_eq!(
+            invoke_context.try_consume(1),
+            Ok(compute_unit_limit.saturating_sub(1))
+        );
+    }
+
+    #[test_case(true; "with log collector")]
+    #[test_case(false; "without log collector")]
+    fn test_log(with_log_collector: bool) {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        if !with_log_collector {
+            invoke_context.log_collector = None;
+        }
+        let syscall_base_cost = invoke_context.get_execution_cost().syscall_base_cost;
+        let long_message = "x".repeat(syscall_base_cost.saturating_add(1) as usize);
+        invoke_context.mock_set_remaining(1_000);
+
+        invoke_context.log("short").unwrap();
+        invoke_context.log(&long_message).unwrap();
+        invoke_context.log_fmt(format_args!("{} {}", 1, 2)).unwrap();
+        assert_eq!(
+            invoke_context.get_remaining_compute_units(),
+            1_000u64
+                .saturating_sub(syscall_base_cost)
+                .saturating_sub(long_message.len() as u64)
+                .saturating_sub(syscall_base_cost)
+        );
+        if let Some(log_collector) = invoke_context.get_log_collector() {
+            assert_eq!(
+                log_collector.borrow().get_recorded_content(),
+                &["short".to_string(), long_message, "1 2".to_string()]
+            );
+        }
+
+        invoke_context.mock_set_remaining(0);
+        assert_eq!(
+            invoke_context.log("short"),
+            Err(InstructionError::ComputationalBudgetExceeded)
+        );
+    }
+
+    #[test_case(true; "measured")]
+    #[test_case(false; "not measured")]
+    fn test_measure_execute_time(measure_execute_time: bool) {
+        let program_key = Pubkey::new_unique();
+        let mut program_account = AccountSharedData::new(1, 1, &native_loader::id());
+        program_account.set_executable(true);
+        let transaction_accounts = vec![
+            (
+                Pubkey::new_unique(),
+                AccountSharedData::new(42, 1, &program_key),
+            ),
+            (
+                Pubkey::new_unique(),
+                AccountSharedData::new(84, 1, &Pubkey::new_unique()),
+            ),
+            (program_key, program_account),
+        ];
+        let instruction_accounts = (0..2)
+            .map(|instruction_account_index| InstructionAccount {
+                index_in_transaction: instruction_account_index,
+                index_in_caller: instruction_account_index,
+                index_in_callee: instruction_account_index,
+                is_signer: false,
+                is_writable: false,
+            })
+            .collect::<Vec<_>>();
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let mut program_cache_for_tx_batch = ProgramCacheForTxBatch::default();
+        program_cache_for_tx_batch.replenish(
+            program_key,
+            Arc::new(ProgramCacheEntry::new_builtin(0, 0, MockBuiltin::vm)),
+        );
+        invoke_context.program_cache_for_tx_batch = &mut program_cache_for_tx_batch;
+        invoke_context.set_measure_execute_time(measure_execute_time);
+
+        let instruction_data =
+            bincode::serialize(&MockInstruction::Sleep { duration_ms: 2 }).unwrap();
+        invoke_context
+            .process_instruction(
+                &instruction_data,
+                &instruction_accounts,
+                &[2],
+                &mut 0,
+                &mut ExecuteTimings::default(),
+            )
+            .unwrap();
+        assert!(invoke_context.execute_time.is_none());
+        assert_eq!(
+            invoke_context.timings.execute_us != ExecuteDetailsTimings::default().execute_us,
+            measure_execute_time
+        );
+    }
+
+    #[test]
+    fn test_return_data() {
+        let program_id = solana_pubkey::new_rand();
+        let transaction_accounts = vec![(program_id, AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        invoke_context
+            .transaction_context
+            .get_next_instruction_context()
+            .unwrap()
+            .configure(&[0], &[], &[]);
+        let execution_cost = *invoke_context.get_execution_cost();
+        invoke_context.mock_set_remaining(1_000);
+
+        // Without a current program nothing is charged
+        assert_eq!(
+            invoke_context.set_return_data(vec![42]),
+            Err(InstructionError::CallDepth)
+        );
+        assert_eq!(invoke_context.get_remaining_compute_units(), 1_000);
+
+        invoke_context.push().unwrap();
+        let data = vec![42; MAX_RETURN_DATA];
+        invoke_context.set_return_data(data.clone()).unwrap();
+        assert_eq!(
+            invoke_context.get_return_data(),
+            (program_id, data.as_slice())
+        );
+        let cost = (MAX_RETURN_DATA as u64)
+            .saturating_div(execution_cost.cpi_bytes_per_unit)
+            .saturating_add(execution_cost.syscall_base_cost);
+        assert_eq!(
+            invoke_context.get_remaining_compute_units(),
+            1_000u64.saturating_sub(cost)
+        );
+
+        assert_eq!(
+            invoke_context.set_return_data(vec![0; MAX_RETURN_DATA.saturating_add(1)]),
+            Err(InstructionError::ProgramFailedToComplete)
+        );
+        assert_eq!(
+            invoke_context.get_return_data(),
+            (program_id, data.as_slice())
+        );
+        // A rejected call is not charged
+        assert_eq!(
+            invoke_context.get_remaining_compute_units(),
+            1_000u64.saturating_sub(cost)
+        );
+    }
+
+    #[test]
+    fn test_cost_model() {
+        struct FlatCostModel;
+        impl CostModel for FlatCostModel {
+            fn log_cost(&self, _len: usize) -> u64 {
+                1
+            }
+
+            fn syscall_cost(&self, _name: &str) -> u64 {
+                2
+            }
+
+            fn sysvar_cost(&self, _size: usize) -> u64 {
+                3
+            }
+
+            fn return_data_cost(&self, _len: usize) -> u64 {
+                4
+            }
+
+            fn heap_cost(&self, _heap_bytes: u32) -> u64 {
+                5
+            }
+
+            fn mem_op_cost(&self, _bytes: u64) -> u64 {
+                6
+            }
+        }
+
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let execution_cost = *invoke_context.get_execution_cost();
+        assert_eq!(
+            invoke_context.get_cost_model().log_cost(1_000),
+            execution_cost.log_cost(1_000)
+        );
+        assert_eq!(
+            invoke_context.get_cost_model().syscall_cost("sol_log_"),
+            execution_cost.syscall_base_cost
+        );
+
+        invoke_context.set_cost_model(Arc::new(FlatCostModel));
+        invoke_context
+            .transaction_context
+            .get_next_instruction_context()
+            .unwrap()
+            .configure(&[0], &[], &[]);
+        invoke_context.push().unwrap();
+        invoke_context.mock_set_remaining(100);
+        invoke_context.log("message").unwrap();
+        assert_eq!(
+            invoke_context.get_sysvar_clock().err(),
+            Some(InstructionError::UnsupportedSysvar)
+        );
+        invoke_context.set_return_data(vec![1, 2, 3]).unwrap();
+        assert_eq!(invoke_context.get_remaining_compute_units(), 92);
+        invoke_context
+            .charge_heap_cost(MIN_HEAP_FRAME_BYTES)
+            .unwrap();
+        assert_eq!(invoke_context.get_remaining_compute_units(), 87);
+        invoke_context.charge_memory_op(0).unwrap();
+        assert_eq!(invoke_context.get_remaining_compute_units(), 81);
+    }
+
+    #[test]
+    fn test_trace_frame_lengths() {
+        struct NullTraceSink;
+        impl TraceSink for NullTraceSink {
+            fn record(&mut self, _frame: usize, _row: [u64; 12]) {}
+        }
+
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        for (trace_log, trace_sink) in [
+            (vec![[1; 12], [2; 12]], None),
+            (
+                vec![[3; 12]],
+                Some(Box::new(NullTraceSink) as Box<dyn TraceSink>),
+            ),
+            (vec![], None),
+        ] {
+            invoke_context.set_trace_sink(trace_sink);
+            invoke_context
+                .transaction_context
+                .get_next_instruction_context()
+                .unwrap()
+                .configure(&[0], &[], &[]);
+            invoke_context.push().unwrap();
+            invoke_context
+                .set_syscall_context(SyscallContext {
+                    allocator: BpfAllocator::new(0),
+                    accounts_metadata: Vec::new(),
+                    trace_log,
+                })
+                .unwrap();
+            invoke_context.pop().unwrap();
+        }
+        assert_eq!(invoke_context.instruction_trace_length(), 3);
+        assert_eq!(invoke_context.trace_frame_lengths(), &[2, 1, 0]);
+        assert!(invoke_context.get_traces()[1].is_empty());
+    }
+
+    #[test]
+    fn test_dump_state() {
+        let caller_program_id = solana_pubkey::new_rand();
+        let callee_program_id = solana_pubkey::new_rand();
+        let transaction_accounts = vec![
+            (caller_program_id, AccountSharedData::default()),
+            (callee_program_id, AccountSharedData::default()),
+        ];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        invoke_context.mock_set_remaining(100);
+        let compute_unit_limit = invoke_context.get_compute_budget().compute_unit_limit;
+        assert_eq!(
+            invoke_context.dump_state(),
+            format!(
+                "stack height: 0\nremaining compute units: 100 of {compute_unit_limit}\ncurrent \
+                 program: none\ninvocation stack: []\nsyscall context: none\nloaded programs: []"
+            )
+        );
+
+        let mut program_cache_for_tx_batch = ProgramCacheForTxBatch::default();
+        program_cache_for_tx_batch.replenish(
+            callee_program_id,
+            Arc::new(ProgramCacheEntry::new_builtin(0, 0, MockBuiltin::vm)),
+        );
+        invoke_context.program_cache_for_tx_batch = &mut program_cache_for_tx_batch;
+
+        for program_index in 0..2 {
+            invoke_context
+                .transaction_context
+                .get_next_instruction_context()
+                .unwrap()
+                .configure(&[program_index], &[], &[]);
+            invoke_context.push().unwrap();
+        }
+        assert_eq!(
+            invoke_context.dump_state(),
+            format!(
+                "stack height: 2\nremaining compute units: 100 of {compute_unit_limit}\ncurrent \
+                 program: {callee_program_id}\ninvocation stack: [{caller_program_id}, \
+                 {callee_program_id}]\nsyscall context: not set\nloaded programs: \
+                 [{callee_program_id}]"
+            )
+        );
+
+        // Does not panic while the compute meter is borrowed
+        let _compute_meter = invoke_context.compute_meter.borrow_mut();
+        assert!(invoke_context
+            .dump_state()
+            .contains("remaining compute units: unknown"));
+    }
+
+    #[test]
+    fn test_new_with_shared_meter() {
+        struct MockInvokeContextCallback {}
+        impl InvokeContextCallback for MockInvokeContextCallback {}
+
+        let compute_budget = SVMTransactionExecutionBudget::default();
+        let new_transaction_context = || {
+            TransactionContext::new(
+                vec![(solana_pubkey::new_rand(), AccountSharedData::default())],
+                Rent::default(),
+                compute_budget.max_instruction_stack_depth,
+                compute_budget.max_instruction_trace_length,
+            )
+        };
+        let mut first_transaction_context = new_transaction_context();
+        let mut second_transaction_context = new_transaction_context();
+        let mut first_program_cache_for_tx_batch = ProgramCacheForTxBatch::default();
+        let mut second_program_cache_for_tx_batch = ProgramCacheForTxBatch::default();
+        let feature_set = SVMFeatureSet::default();
+        let sysvar_cache = SysvarCache::default();
+        let new_environment_config = || {
+            EnvironmentConfig::new(
+                Hash::default(),
+                0,
+                &MockInvokeContextCallback {},
+                &feature_set,
+                &sysvar_cache,
+            )
+        };
+        let compute_meter = SharedComputeMeter::new(100);
+        let mut first_invoke_context = InvokeContext::new_with_shared_meter(
+            &mut first_transaction_context,
+            &mut first_program_cache_for_tx_batch,
+            new_environment_config(),
+            None,
+            compute_budget,
+            SVMTransactionExecutionCost::default(),
+            compute_meter.clone(),
+        );
+        let second_invoke_context = InvokeContext::new_with_shared_meter(
+            &mut second_transaction_context,
+            &mut second_program_cache_for_tx_batch,
+            new_environment_config(),
+            None,
+            compute_budget,
+            SVMTransactionExecutionCost::default(),
+            compute_meter.clone(),
+        );
+
+        assert_eq!(second_invoke_context.try_consume(40), Ok(60));
+        assert_eq!(first_invoke_context.get_remaining_compute_units(), 60);
+        assert_eq!(first_invoke_context.try_consume(60), Ok(0));
+        assert_eq!(
+            second_invoke_context.try_consume(1),
+            Err(InstructionError::ComputationalBudgetExceeded)
+        );
+        assert_eq!(compute_meter.remaining(), 0);
+
+        // Raising the meter in one context raises the ceiling of the other one as well
+        first_invoke_context.mock_set_remaining(200);
+        assert!(second_invoke_context.compute_meter_invariant_ok());
+        assert_eq!(second_invoke_context.try_consume(50), Ok(150));
+
+        // Resetting one context does not refill the meter of the other one
+        first_invoke_context.reset();
+        assert_eq!(compute_meter.remaining(), 150);
+        assert_eq!(second_invoke_context.get_remaining_compute_units(), 150);
+    }
+
+    #[test_case(2, 2, 1.0; "full")]
+    #[test_case(2, 1, 0.5; "half")]
+    #[test_case(2, 0, 0.0; "exhausted")]
+    #[test_case(0, 0, 0.0; "zero limit")]
+    fn test_remaining_as_fraction(compute_unit_limit: u64, remaining: u64, expected: f64) {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        invoke_context.compute_budget.compute_unit_limit = compute_unit_limit;
+        invoke_context.mock_set_remaining(remaining);
+        assert_eq!(invoke_context.remaining_as_fraction(), expected);
+    }
+
+    #[test]
+    fn test_prepare_instructions() {
+        let callee_program_id = solana_pubkey::new_rand();
+        let known_key = solana_pubkey::new_rand();
+        let unknown_key = solana_pubkey::new_rand();
+        let mut program_account = AccountSharedData::new(1, 0, &native_loader::id());
+        program_account.set_executable(true);
+        let transaction_accounts = vec![
+            (known_key, AccountSharedData::default()),
+            (callee_program_id, program_account),
+        ];
+        let instruction_accounts = (0..2)
+            .map(|instruction_account_index| InstructionAccount {
+                index_in_transaction: instruction_account_index,
+                index_in_caller: instruction_account_index,
+                index_in_callee: instruction_account_index,
+                is_signer: false,
+                is_writable: instruction_account_index == 0,
+            })
+            .collect::<Vec<_>>();
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        invoke_context
+            .transaction_context
+            .get_next_instruction_context()
+            .unwrap()
+            .configure(&[1], &instruction_accounts, &[]);
+        invoke_context.push().unwrap();
+
+        let instruction = |account_meta: AccountMeta| {
+            StableInstruction::from(Instruction::new_with_bytes(
+                callee_program_id,
+                &[],
+                vec![account_meta],
+            ))
+        };
+        let prepared_instructions = invoke_context
+            .prepare_instructions(
+                &[
+                    instruction(AccountMeta::new(known_key, false)),
+                    instruction(AccountMeta::new_readonly(known_key, false)),
+                ],
+                &[],
+            )
+            .unwrap();
+        assert_eq!(prepared_instructions.len(), 2);
+        assert_eq!(prepared_instructions[0].program_indices, vec![1]);
+        assert!(prepared_instructions[0].instruction_accounts[0].is_writable);
+        assert!(!prepared_instructions[1].instruction_accounts[0].is_writable);
+
+        assert_eq!(
+            invoke_context.prepare_instructions(
+                &[
+                    instruction(AccountMeta::new(known_key, false)),
+                    instruction(AccountMeta::new(unknown_key, false)),
+                    instruction(AccountMeta::new(known_key, false)),
+                ],
+                &[],
+            ),
+            Err((1, InstructionError::MissingAccount))
+        );
+        let log_collector = invoke_context.get_log_collector().unwrap();
+        assert_eq!(
+            log_collector.borrow().get_recorded_content(),
+            &[
+                format!("Instruction references an unknown account {unknown_key}"),
+                "Instruction 1 of the batch failed to prepare".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_consume_hook() {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let consumptions = Rc::new(RefCell::new(Vec::new()));
+        let hook_consumptions = consumptions.clone();
+        invoke_context.set_consume_hook(Some(Box::new(move |amount, remaining| {
+            hook_consumptions.borrow_mut().push((amount, remaining));
+        })));
+        invoke_context.mock_set_remaining(100);
+
+        invoke_context.consume(10);
+        invoke_context.try_consume(20).unwrap();
+        invoke_context.consume_checked(30).unwrap();
+        assert_eq!(
+            invoke_context.try_consume(50),
+            Err(InstructionError::ComputationalBudgetExceeded)
+        );
+        assert_eq!(
+            *consumptions.borrow(),
+            vec![(10, 90), (20, 70), (30, 40), (50, 0)]
+        );
+
+        invoke_context.set_consume_hook(None);
+        invoke_context.consume(1);
+        assert_eq!(consumptions.borrow().len(), 4);
+    }
+
+    #[cfg(feature = "dev-context-only-utils")]
+    #[test]
+    fn test_push_mock_frame() {
+        let program_id = solana_pubkey::new_rand();
+        let transaction_accounts = vec![
+            (
+                solana_pubkey::new_rand(),
+                AccountSharedData::new(1, 1, &program_id),
+            ),
+            (program_id, AccountSharedData::default()),
+        ];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        assert_eq!(
+            invoke_context.push_mock_frame(solana_pubkey::new_rand(), &[]),
+            Err(InstructionError::MissingAccount)
+        );
+
+        invoke_context.push_mock_frame(program_id, &[0, 0]).unwrap();
+        assert_eq!(invoke_context.get_stack_height(), 1);
+        assert_eq!(invoke_context.current_program_id(), Ok(&program_id));
+        assert!(invoke_context.get_syscall_context().is_ok());
+        let instruction_context = invoke_context
+            .transaction_context
+            .get_current_instruction_context()
+            .unwrap();
+        assert_eq!(instruction_context.get_number_of_instruction_accounts(), 2);
+        assert!(instruction_context
+            .is_instruction_account_duplicate(1)
+            .unwrap()
+            .is_some());
+        let account = instruction_context
+            .try_borrow_instruction_account(invoke_context.transaction_context, 0)
+            .unwrap();
+        assert!(account.is_writable());
+        assert_eq!(account.get_owner(), &program_id);
+        drop(account);
+
+        invoke_context.pop_mock_frame().unwrap();
+        assert_eq!(invoke_context.get_stack_height(), 0);
+        assert!(invoke_context.syscall_context.is_empty());
+    }
+
+    #[test]
+    fn test_log_charge_independent_of_log_collector() {
+        let consumed = [true, false].map(|with_log_collector| {
+            let transaction_accounts =
+                vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+            with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+            if !with_log_collector {
+                invoke_context.log_collector = None;
+            }
+            invoke_context.mock_set_remaining(10_000);
+            invoke_context.log("message").unwrap();
+            invoke_context.log_data(&[b"field", &[0; 200]]).unwrap();
+            10_000u64.saturating_sub(invoke_context.get_remaining_compute_units())
+        });
+        assert_eq!(consumed[0], consumed[1]);
+    }
+
+    #[test]
+    fn test_syscall_depth() {
+        let transaction_accounts = vec![
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+        ];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        assert_eq!(invoke_context.syscall_depth(), 0);
+
+        for (program_index, set_syscall_context, expected_syscall_depth) in
+            [(0, true, 1), (1, false, 1), (2, true, 2)]
+        {
+            invoke_context
+                .transaction_context
+                .get_next_instruction_context()
+                .unwrap()
+                .configure(&[program_index], &[], &[]);
+            invoke_context.push().unwrap();
+            if set_syscall_context {
+                invoke_context
+                    .set_syscall_context(SyscallContext {
+                        allocator: BpfAllocator::new(0),
+                        accounts_metadata: Vec::new(),
+                        trace_log: Vec::new(),
+                    })
+                    .unwrap();
+            }
+            assert_eq!(invoke_context.syscall_depth(), expected_syscall_depth);
+        }
+
+        for expected_syscall_depth in [1, 1, 0] {
+            invoke_context.pop().unwrap();
+            assert_eq!(invoke_context.syscall_depth(), expected_syscall_depth);
+        }
+    }
+
+    #[test]
+    fn test_process_instruction_detailed() {
+        let callee_program_id = solana_pubkey::new_rand();
+        let mut program_account = AccountSharedData::new(1, 1, &native_loader::id());
+        program_account.set_executable(true);
+        let transaction_accounts = vec![
+            (
+                solana_pubkey::new_rand(),
+                AccountSharedData::new(42, 1, &callee_program_id),
+            ),
+            (
+                solana_pubkey::new_rand(),
+                AccountSharedData::new(84, 1, &solana_pubkey::new_rand()),
+            ),
+            (
+                solana_pubkey::new_rand(),
+                AccountSharedData::new(168, 1, &solana_pubkey::new_rand()),
+            ),
+            (callee_program_id, program_account),
+        ];
+        let instruction_accounts = (0..4)
+            .map(|instruction_account_index| InstructionAccount {
+                index_in_transaction: instruction_account_index,
+                index_in_caller: instruction_account_index,
+                index_in_callee: instruction_account_index,
+                is_signer: false,
+                is_writable: false,
+            })
+            .collect::<Vec<_>>();
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let mut program_cache_for_tx_batch = ProgramCacheForTxBatch::default();
+        program_cache_for_tx_batch.replenish(
+            callee_program_id,
+            Arc::new(ProgramCacheEntry::new_builtin(0, 1, MockBuiltin::vm)),
+        );
+        invoke_context.program_cache_for_tx_batch = &mut program_cache_for_tx_batch;
+
+        let mut compute_units_consumed = 0;
+        let instruction_data = bincode::serialize(&MockInstruction::ConsumeComputeUnits {
+            compute_units_to_consume: 10,
+            desired_result: Err(InstructionError::GenericError),
+        })
+        .unwrap();
+        let result = invoke_context.process_instruction_detailed(
+            &instruction_data,
+            &instruction_accounts,
+            &[3],
+            &mut compute_units_consumed,
+            &mut ExecuteTimings::default(),
+        );
+        assert_eq!(
+            result,
+            Err(DetailedInstructionError {
+                error: InstructionError::GenericError,
+                program_id: callee_program_id,
+                compute_consumed: MOCK_BUILTIN_COMPUTE_UNIT_COST.saturating_add(10),
+                stack_height: 1,
+            })
+        );
+        assert_eq!(
+            compute_units_consumed,
+            MOCK_BUILTIN_COMPUTE_UNIT_COST.saturating_add(10)
+        );
+        assert_eq!(invoke_context.get_stack_height(), 0);
+
+        let instruction_data = bincode::serialize(&MockInstruction::NoopSuccess).unwrap();
+        assert_eq!(
+            invoke_context.process_instruction_detailed(
+                &instruction_data,
+                &instruction_accounts,
+                &[3],
+                &mut compute_units_consumed,
+                &mut ExecuteTimings::default(),
+            ),
+            Ok(())
+        );
+        assert_eq!(compute_units_consumed, MOCK_BUILTIN_COMPUTE_UNIT_COST);
+    }
+
+    #[test]
+    fn test_estimate_cpi_serialization_cost() {
+        let program_id = solana_pubkey::new_rand();
+        let transaction_accounts = vec![
+            (
+                solana_pubkey::new_rand(),
+                AccountSharedData::new(1, 1_000, &program_id),
+            ),
+            (
+                solana_pubkey::new_rand(),
+                AccountSharedData::new(1, 500, &program_id),
+            ),
+            (program_id, AccountSharedData::default()),
+        ];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let execution_cost = *invoke_context.get_execution_cost();
+        let account_keys = [0, 1].map(|index| {
+            *invoke_context
+                .transaction_context
+                .get_key_of_account_at_index(index)
+                .unwrap()
+        });
+        let instruction = StableInstruction::from(Instruction::new_with_bytes(
+            program_id,
+            &[0; 100],
+            vec![
+                AccountMeta::new(account_keys[0], false),
+                AccountMeta::new_readonly(account_keys[1], false),
+                AccountMeta::new(account_keys[0], false),
+                AccountMeta::new_readonly(solana_pubkey::new_rand(), false),
+            ],
+        ));
+
+        let instruction_bytes = 4u64
+            .saturating_mul(size_of::<AccountMeta>() as u64)
+            .saturating_add(100);
+        assert_eq!(
+            invoke_context.estimate_cpi_serialization_cost(&instruction),
+            execution_cost
+                .invoke_units
+                .saturating_add(instruction_bytes.saturating_div(execution_cost.cpi_bytes_per_unit))
+                .saturating_add(1_000u64.saturating_div(execution_cost.cpi_bytes_per_unit))
+                .saturating_add(500u64.saturating_div(execution_cost.cpi_bytes_per_unit))
+        );
+    }
+
+    #[test_case(false; "budget increase not allowed")]
+    #[test_case(true; "budget increase allowed")]
+    fn test_set_compute_unit_limit(allow_budget_increase: bool) {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let compute_unit_limit = invoke_context.get_compute_budget().compute_unit_limit;
+        invoke_context.set_allow_budget_increase(allow_budget_increase);
+
+        invoke_context.set_compute_unit_limit(1_000).unwrap();
+        assert_eq!(invoke_context.get_remaining_compute_units(), 1_000);
+        invoke_context
+            .set_compute_unit_limit(compute_unit_limit)
+            .unwrap();
+        assert_eq!(
+            invoke_context.get_remaining_compute_units(),
+            compute_unit_limit
+        );
+
+        let result = invoke_context.set_compute_unit_limit(compute_unit_limit.saturating_add(1));
+        if allow_budget_increase {
+            assert_eq!(result, Ok(()));
+            assert_eq!(
+                invoke_context.get_remaining_compute_units(),
+                compute_unit_limit.saturating_add(1)
+            );
+        } else {
+            assert_eq!(result, Err(InstructionError::InvalidArgument));
+            assert_eq!(
+                invoke_context.get_remaining_compute_units(),
+                compute_unit_limit
+            );
+        }
+        assert!(invoke_context.compute_meter_invariant_ok());
+    }
+
+    #[test_case(0, 0; "zero fee")]
+    #[test_case(250, 2_000; "normal fee")]
+    #[test_case(u64::MAX, 10_000; "capped fee")]
+    fn test_apply_priority_boost(priority_fee: u64, expected_granted: u64) {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        invoke_context.mock_set_remaining(5_000);
+        invoke_context.set_priority_boost_cap(15_000);
+
+        assert_eq!(
+            invoke_context.apply_priority_boost(priority_fee, 100),
+            expected_granted
+        );
+        assert_eq!(
+            invoke_context.get_remaining_compute_units(),
+            5_000u64.saturating_add(expected_granted)
+        );
+        assert!(invoke_context.compute_meter_invariant_ok());
+
+        assert_eq!(invoke_context.apply_priority_boost(priority_fee, 0), 0);
+        invoke_context.set_priority_boost_cap(0);
+        assert_eq!(invoke_context.apply_priority_boost(priority_fee, 100), 0);
+        assert_eq!(
+            invoke_context.get_remaining_compute_units(),
+            5_000u64.saturating_add(expected_granted)
+        );
+    }
+
+    #[test_case(0, Ok(()), 5_000; "zero contention")]
+    #[test_case(4, Ok(()), 4_800; "low contention")]
+    #[test_case(u64::MAX, Err(InstructionError::ComputationalBudgetExceeded), 0; "high contention")]
+    fn test_apply_contention_adjustment(
+        contention_level: u64,
+        expected_result: Result<(), InstructionError>,
+        expected_remaining: u64,
+    ) {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        invoke_context.mock_set_remaining(5_000);
+
+        assert_eq!(
+            invoke_context.apply_contention_adjustment(contention_level, 50),
+            expected_result
+        );
+        assert_eq!(
+            invoke_context.get_remaining_compute_units(),
+            expected_remaining
+        );
+    }
+
+    #[test]
+    fn test_promises() {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        assert_eq!(
+            invoke_context.poll_promise(0),
+            Err(InstructionError::InvalidArgument)
+        );
+
+        let resolved = invoke_context.register_promise();
+        let failed = invoke_context.register_promise();
+        assert_ne!(resolved, failed);
+        assert_eq!(invoke_context.poll_promise(resolved), Ok(None));
+        assert_eq!(invoke_context.poll_promise(failed), Ok(None));
+
+        invoke_context
+            .resolve_promise(resolved, vec![1, 2, 3])
+            .unwrap();
+        assert_eq!(
+            invoke_context.poll_promise(resolved),
+            Ok(Some([1, 2, 3].as_slice()))
+        );
+        assert_eq!(invoke_context.poll_promise(failed), Ok(None));
+
+        invoke_context
+            .fail_promise(failed, InstructionError::InvalidAccountData)
+            .unwrap();
+        assert_eq!(
+            invoke_context.poll_promise(failed),
+            Err(InstructionError::InvalidAccountData)
+        );
+
+        assert_eq!(
+            invoke_context.resolve_promise(failed, vec![]),
+            Err(InstructionError::InvalidArgument)
+        );
+        assert_eq!(
+            invoke_context.fail_promise(u64::MAX, InstructionError::GenericError),
+            Err(InstructionError::InvalidArgument)
+        );
+    }
+
+    #[test]
+    fn test_max_trace_rows() {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        invoke_context.set_max_trace_rows(Some(3));
+        for trace_log in [
+            vec![[1; 12], [2; 12]],
+            vec![[3; 12], [4; 12]],
+            vec![[5; 12]],
+        ] {
+            invoke_context
+                .transaction_context
+                .get_next_instruction_context()
+                .unwrap()
+                .configure(&[0], &[], &[]);
+            invoke_context.push().unwrap();
+            invoke_context
+                .set_syscall_context(SyscallContext {
+                    allocator: BpfAllocator::new(0),
+                    accounts_metadata: Vec::new(),
+                    trace_log,
+                })
+                .unwrap();
+            invoke_context.pop().unwrap();
+        }
+        assert!(invoke_context.traces_were_truncated());
+        assert_eq!(
+            invoke_context.get_traces(),
+            &vec![vec![[1; 12], [2; 12]], vec![[3; 12]], vec![]]
+        );
+
+        invoke_context.reset();
+        assert!(!invoke_context.traces_were_truncated());
+        invoke_context
+            .transaction_context
+            .get_next_instruction_context()
+            .unwrap()
+            .configure(&[0], &[], &[]);
+        invoke_context.push().unwrap();
+        invoke_context
+            .set_syscall_context(SyscallContext {
+                allocator: BpfAllocator::new(0),
+                accounts_metadata: Vec::new(),
+                trace_log: Vec::new(),
+            })
+            .unwrap();
+        for row in 0..5 {
+            invoke_context.trace([row; 12]);
+        }
+        assert!(invoke_context.traces_were_truncated());
+        assert_eq!(
+            invoke_context.get_syscall_context().unwrap().trace_log,
+            vec![[0; 12], [1; 12], [2; 12]]
+        );
+    }
+
+    #[test]
+    fn test_clone_for_simulation() {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        let mut simulation_transaction_context =
+            TransactionContext::new(transaction_accounts.clone(), Rent::default(), 20, 20);
+        let mut simulation_program_cache = ProgramCacheForTxBatch::default();
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        invoke_context.mock_set_remaining(1_000);
+        invoke_context.consume_with_label(100, "setup").unwrap();
+        let promise_id = invoke_context.register_promise();
+
+        let mut simulation = invoke_context.clone_for_simulation(
+            &mut simulation_transaction_context,
+            &mut simulation_program_cache,
+        );
+        assert_eq!(simulation.get_remaining_compute_units(), 900);
+        assert_eq!(simulation.compute_breakdown().get("setup"), Some(&100));
+        assert_eq!(simulation.poll_promise(promise_id), Ok(None));
+        assert!(simulation.get_log_collector().is_some());
+
+        simulation.consume_checked(500).unwrap();
+        simulation.resolve_promise(promise_id, vec![1]).unwrap();
+        assert_eq!(simulation.get_remaining_compute_units(), 400);
+        assert_eq!(invoke_context.get_remaining_compute_units(), 900);
+        assert_eq!(invoke_context.poll_promise(promise_id), Ok(None));
+    }
+
+    #[test]
+    fn test_verify_writable_not_executable() {
+        let mut program_account = AccountSharedData::new(1, 1, &native_loader::id());
+        program_account.set_executable(true);
+        let transaction_accounts = vec![
+            (
+                solana_pubkey::new_rand(),
+                AccountSharedData::new(1, 1, &Pubkey::default()),
+            ),
+            (solana_pubkey::new_rand(), program_account),
+        ];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let instruction_account = |index_in_transaction, is_writable| InstructionAccount {
+            index_in_transaction,
+            index_in_caller: index_in_transaction,
+            index_in_callee: index_in_transaction,
+            is_signer: false,
+            is_writable,
+        };
+
+        assert_eq!(
+            invoke_context.verify_writable_not_executable(&[
+                instruction_account(0, true),
+                instruction_account(1, false),
+            ]),
+            Ok(())
+        );
+        assert_eq!(
+            invoke_context.verify_writable_not_executable(&[
+                instruction_account(0, true),
+                instruction_account(1, true),
+            ]),
+            Err(InstructionError::ExecutableModified)
+        );
+    }
+
+    #[test]
+    fn test_vm_backend() {
+        struct MockVmBackend {
+            executed: Rc<Cell<usize>>,
+            compute_units: u64,
+        }
+        impl VmBackend for MockVmBackend {
+            fn execute(
+                &self,
+                _entry: &ProgramCacheEntry,
+                invoke_context: &mut InvokeContext,
+            ) -> Result<(), InstructionError> {
+                assert_eq!(invoke_context.get_stack_height(), 1);
+                assert!(invoke_context
+                    .transaction_context
+                    .get_return_data()
+                    .1
+                    .is_empty());
+                self.executed.set(self.executed.get().saturating_add(1));
+                invoke_context.try_consume(self.compute_units)?;
+                Ok(())
+            }
+        }
+
+        let program_id = solana_pubkey::new_rand();
+        let mut program_account = AccountSharedData::new(1, 1, &native_loader::id());
+        program_account.set_executable(true);
+        let transaction_accounts = vec![(program_id, program_account)];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let mut program_cache_for_tx_batch = ProgramCacheForTxBatch::default();
+        program_cache_for_tx_batch.replenish(
+            program_id,
+            Arc::new(ProgramCacheEntry::new_builtin(0, 1, MockBuiltin::vm)),
+        );
+        invoke_context.program_cache_for_tx_batch = &mut program_cache_for_tx_batch;
+        let executed = Rc::new(Cell::new(0));
+        invoke_context.set_vm_backend(Some(Rc::new(MockVmBackend {
+            executed: Rc::clone(&executed),
+            compute_units: 5,
+        })));
+        invoke_context
+            .transaction_context
+            .set_return_data(program_id, vec![1, 2, 3])
+            .unwrap();
+
+        let mut compute_units_consumed = 0;
+        assert_eq!(
+            invoke_context.process_instruction(
+                &[],
+                &[],
+                &[0],
+                &mut compute_units_consumed,
+                &mut ExecuteTimings::default(),
+            ),
+            Ok(())
+        );
+        assert_eq!(executed.get(), 1);
+        assert_eq!(compute_units_consumed, 5);
+        assert_eq!(invoke_context.get_stack_height(), 0);
+        assert_eq!(
+            invoke_context
+                .get_log_collector()
+                .unwrap()
+                .borrow()
+                .get_recorded_content(),
+            &[
+                format!("Program {program_id} invoke [1]"),
+                format!("Program {program_id} success"),
+            ]
+        );
+
+        // Builtins executed by a backend still have to consume compute units
+        invoke_context.set_vm_backend(Some(Rc::new(MockVmBackend {
+            executed: Rc::clone(&executed),
+            compute_units: 0,
+        })));
+        assert_eq!(
+            invoke_context.process_instruction(
+                &[],
+                &[],
+                &[0],
+                &mut compute_units_consumed,
+                &mut ExecuteTimings::default(),
+            ),
+            Err(InstructionError::BuiltinProgramsMustConsumeComputeUnits)
+        );
+        assert_eq!(executed.get(), 2);
+        assert_eq!(compute_units_consumed, 0);
+    }
+
+    #[test_case(bpf_loader::id(), Ok(bpf_loader::id()); "bpf loader v2")]
+    #[test_case(bpf_loader_upgradeable::id(), Ok(bpf_loader_upgradeable::id()); "bpf loader v3")]
+    #[test_case(native_loader::id(), Err(InstructionError::UnsupportedProgramId); "native loader")]
+    #[test_case(Pubkey::new_unique(), Err(InstructionError::UnsupportedProgramId); "unsupported")]
+    fn test_get_loader_id(owner_id: Pubkey, expected_result: Result<Pubkey, InstructionError>) {
+        let transaction_accounts = vec![(
+            solana_pubkey::new_rand(),
+            AccountSharedData::new(1, 0, &owner_id),
+        )];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        assert_eq!(
+            invoke_context.get_loader_id(),
+            Err(InstructionError::CallDepth)
+        );
+
+        invoke_context
+            .transaction_context
+            .get_next_instruction_context()
+            .unwrap()
+            .configure(&[0], &[], &[]);
+        invoke_context.push().unwrap();
+        assert_eq!(invoke_context.get_loader_id(), expected_result);
+    }
+
+    #[test]
+    fn test_reserve_compute_units() {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        assert_eq!(
+            invoke_context.reserve_compute_units(10),
+            Err(InstructionError::CallDepth)
+        );
+
+        invoke_context.mock_set_remaining(1_000);
+        invoke_context
+            .transaction_context
+            .get_next_instruction_context()
+            .unwrap()
+            .configure(&[0], &[], &[]);
+        invoke_context.push().unwrap();
+        assert_eq!(
+            invoke_context.reserve_compute_units(1_001),
+            Err(InstructionError::ComputationalBudgetExceeded)
+        );
+        invoke_context.reserve_compute_units(300).unwrap();
+        assert_eq!(invoke_context.get_remaining_compute_units(), 700);
+        assert_eq!(invoke_context.get_reserved_compute_units(), 300);
+
+        assert_eq!(
+            invoke_context.try_consume(800),
+            Err(InstructionError::ComputationalBudgetExceeded)
+        );
+        assert_eq!(invoke_context.get_remaining_compute_units(), 0);
+        invoke_context.consume_reserved(200).unwrap();
+        assert_eq!(invoke_context.get_reserved_compute_units(), 100);
+        assert_eq!(invoke_context.pop_with_usage(), Ok(900));
+        assert_eq!(invoke_context.get_remaining_compute_units(), 100);
+
+        invoke_context
+            .transaction_context
+            .get_next_instruction_context()
+            .unwrap()
+            .configure(&[0], &[], &[]);
+        invoke_context.push().unwrap();
+        invoke_context.reserve_compute_units(50).unwrap();
+        assert_eq!(
+            invoke_context.consume_reserved(60),
+            Err(InstructionError::ComputationalBudgetExceeded)
+        );
+        assert_eq!(invoke_context.get_reserved_compute_units(), 0);
+        invoke_context.pop().unwrap();
+        assert_eq!(invoke_context.get_remaining_compute_units(), 50);
+    }
+
+    #[test]
+    fn test_reserve_survives_failed_push() {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let configure = |invoke_context: &mut InvokeContext| {
+            invoke_context
+                .transaction_context
+                .get_next_instruction_context()
+                .unwrap()
+                .configure(&[0], &[], &[]);
+        };
+        invoke_context.mock_set_remaining(1_000);
+        configure(&mut invoke_context);
+        invoke_context.push().unwrap();
+        invoke_context.reserve_compute_units(300).unwrap();
+
+        // Fill the instruction trace with nested frames until the next push fails
+        let result = loop {
+            configure(&mut invoke_context);
+            if let Err(err) = invoke_context.push() {
+                break err;
+            }
+            invoke_context.pop().unwrap();
+        };
+        assert_eq!(result, InstructionError::MaxInstructionTraceLengthExceeded);
+        assert_eq!(invoke_context.get_reserved_compute_units(), 300);
+
+        invoke_context.consume_reserved(100).unwrap();
+        assert_eq!(invoke_context.pop_with_usage(), Ok(100));
+        assert_eq!(invoke_context.get_remaining_compute_units(), 900);
+    }
+
+    #[test]
+    fn test_reserve_not_consumed_until_spent() {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let compute_unit_limit = invoke_context.get_compute_budget().compute_unit_limit;
+        invoke_context.set_soft_limit_fraction(Some(0.5));
+        invoke_context.set_frame_cu_cap(Some(100));
+        invoke_context
+            .transaction_context
+            .get_next_instruction_context()
+            .unwrap()
+            .configure(&[0], &[], &[]);
+        invoke_context.push().unwrap();
+
+        invoke_context
+            .reserve_compute_units(compute_unit_limit.saturating_div(2))
+            .unwrap();
+        invoke_context.try_consume(1).unwrap();
+        assert_eq!(invoke_context.get_frame_consumed_compute_units(), 1);
+        assert_eq!(invoke_context.execution_interrupt(), None);
+        assert!(!invoke_context
+            .get_log_collector()
+            .unwrap()
+            .borrow()
+            .get_recorded_content()
+            .iter()
+            .any(|message| message.starts_with("Compute unit soft limit reached")));
+
+        invoke_context.consume_reserved(100).unwrap();
+        assert_eq!(invoke_context.get_frame_consumed_compute_units(), 101);
+        assert_eq!(
+            invoke_context.try_consume(0),
+            Err(InstructionError::ComputationalBudgetExceeded)
+        );
+        assert_eq!(
+            invoke_context.execution_interrupt(),
+            Some(ExecutionInterrupt::FrameCuCapExceeded)
+        );
+    }
+
+    #[test]
+    fn test_verify_trace() {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let recorded = vec![vec![[1; 12], [2; 12]], vec![[3; 12]]];
+        for trace_log in recorded.iter().cloned() {
+            invoke_context
+                .transaction_context
+                .get_next_instruction_context()
+                .unwrap()
+                .configure(&[0], &[], &[]);
+            invoke_context.push().unwrap();
+            invoke_context
+                .set_syscall_context(SyscallContext {
+                    allocator: BpfAllocator::new(0),
+                    accounts_metadata: Vec::new(),
+                    trace_log,
+                })
+                .unwrap();
+            invoke_context.pop().unwrap();
+        }
+        assert_eq!(invoke_context.verify_trace(&recorded), Ok(()));
+
+        let mut diverging = recorded.clone();
+        diverging[0][1][11] = 42;
+        assert_eq!(
+            invoke_context.verify_trace(&diverging),
+            Err(TraceMismatch::Register {
+                frame: 0,
+                row: 1,
+                register: 11,
+                expected: 42,
+                actual: 2,
+            })
+        );
+
+        let mut diverging = recorded.clone();
+        diverging[1].push([4; 12]);
+        assert_eq!(
+            invoke_context.verify_trace(&diverging),
+            Err(TraceMismatch::RowCount {
+                frame: 1,
+                expected: 2,
+                actual: 1,
+            })
+        );
+
+        assert_eq!(
+            invoke_context.verify_trace(&recorded[..1]),
+            Err(TraceMismatch::FrameCount {
+                expected: 1,
+                actual: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn test_accounts_touched() {
+        let transaction_accounts = vec![
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+        ];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let instruction_account = |index_in_transaction, is_writable| InstructionAccount {
+            index_in_transaction,
+            index_in_caller: index_in_transaction,
+            index_in_callee: index_in_transaction,
+            is_signer: false,
+            is_writable,
+        };
+
+        for (instruction_accounts, track_accounts_touched) in [
+            (vec![instruction_account(3, true)], false),
+            (
+                vec![instruction_account(2, false), instruction_account(1, true)],
+                true,
+            ),
+            (
+                vec![instruction_account(1, false), instruction_account(2, true)],
+                true,
+            ),
+        ] {
+            invoke_context.set_track_accounts_touched(track_accounts_touched);
+            invoke_context
+                .transaction_context
+                .get_next_instruction_context()
+                .unwrap()
+                .configure(&[0], &instruction_accounts, &[]);
+            invoke_context.push().unwrap();
+        }
+        assert_eq!(invoke_context.accounts_touched(), &[2, 1]);
+        assert_eq!(invoke_context.writable_accounts_touched(), &[1, 2]);
+
+        invoke_context.reset();
+        assert!(invoke_context.accounts_touched().is_empty());
+        assert!(invoke_context.writable_accounts_touched().is_empty());
+    }
+
+    #[test]
+    fn test_set_log_collector() {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        invoke_context.log("before").unwrap();
+
+        let log_collector = LogCollector::new_ref();
+        let previous_log_collector = invoke_context
+            .set_log_collector(Some(Rc::clone(&log_collector)))
+            .unwrap();
+        invoke_context.log("after").unwrap();
+        assert_eq!(
+            previous_log_collector.borrow().get_recorded_content(),
+            &["before".to_string()]
+        );
+        assert_eq!(
+            log_collector.borrow().get_recorded_content(),
+            &["after".to_string()]
+        );
+
+        let remaining = invoke_context.get_remaining_compute_units();
+        assert!(invoke_context.set_log_collector(None).is_some());
+        invoke_context.log("dropped").unwrap();
+        assert_eq!(log_collector.borrow().get_recorded_content().len(), 1);
+        assert!(invoke_context.get_remaining_compute_units() < remaining);
+    }
+
+    #[test]
+    fn test_zero_compute_budget() {
+        let callee_program_id = solana_pubkey::new_rand();
+        let mut program_account = AccountSharedData::new(1, 1, &native_loader::id());
+        program_account.set_executable(true);
+        let transaction_accounts = vec![
+            (
+                solana_pubkey::new_rand(),
+                AccountSharedData::new(42, 1, &callee_program_id),
+            ),
+            (
+                solana_pubkey::new_rand(),
+                AccountSharedData::new(84, 1, &solana_pubkey::new_rand()),
+            ),
+            (
+                solana_pubkey::new_rand(),
+                AccountSharedData::new(168, 1, &solana_pubkey::new_rand()),
+            ),
+            (callee_program_id, program_account),
+        ];
+        let instruction_accounts = (0..4)
+            .map(|instruction_account_index| InstructionAccount {
+                index_in_transaction: instruction_account_index,
+                index_in_caller: instruction_account_index,
+                index_in_callee: instruction_account_index,
+                is_signer: false,
+                is_writable: instruction_account_index == 0,
+            })
+            .collect::<Vec<_>>();
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let mut program_cache_for_tx_batch = ProgramCacheForTxBatch::default();
+        program_cache_for_tx_batch.replenish(
+            callee_program_id,
+            Arc::new(ProgramCacheEntry::new_builtin(0, 1, MockBuiltin::vm)),
+        );
+        invoke_context.program_cache_for_tx_batch = &mut program_cache_for_tx_batch;
+        invoke_context.compute_budget.compute_unit_limit = 0;
+        invoke_context.reset();
+        assert_eq!(invoke_context.get_remaining_compute_units(), 0);
+
+        let instruction_data = bincode::serialize(&MockInstruction::ModifyOwned).unwrap();
+        let mut compute_units_consumed = 0;
+        assert_eq!(
+            invoke_context.process_instruction(
+                &instruction_data,
+                &instruction_accounts,
+                &[3],
+                &mut compute_units_consumed,
+                &mut ExecuteTimings::default(),
+            ),
+            Err(InstructionError::ComputationalBudgetExceeded)
+        );
+        assert_eq!(compute_units_consumed, 0);
+        assert_eq!(
+            invoke_context
+                .transaction_context
+                .accounts()
+                .try_borrow(0)
+                .unwrap()
+                .data(),
+            &[0]
+        );
+        assert_eq!(invoke_context.get_stack_height(), 0);
+
+        assert_eq!(
+            invoke_context.try_consume(1),
+            Err(InstructionError::ComputationalBudgetExceeded)
+        );
+        assert_eq!(
+            invoke_context.log("message"),
+            Err(InstructionError::ComputationalBudgetExceeded)
+        );
+    }
+
+    #[test]
+    fn test_account_counts() {
+        let transaction_accounts = vec![
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+        ];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        assert_eq!(invoke_context.total_accounts(), 3);
+        assert_eq!(
+            invoke_context.instruction_account_count(),
+            Err(InstructionError::CallDepth)
+        );
+
+        let instruction_accounts = (0..3)
+            .map(|index_in_transaction| InstructionAccount {
+                index_in_transaction,
+                index_in_caller: index_in_transaction,
+                index_in_callee: index_in_transaction,
+                is_signer: false,
+                is_writable: false,
+            })
+            .collect::<Vec<_>>();
+        for (instruction_accounts, expected_count) in [
+            (&instruction_accounts[..], 3),
+            (&instruction_accounts[1..2], 1),
+        ] {
+            invoke_context
+                .transaction_context
+                .get_next_instruction_context()
+                .unwrap()
+                .configure(&[0], instruction_accounts, &[]);
+            invoke_context.push().unwrap();
+            assert_eq!(
+                invoke_context.instruction_account_count(),
+                Ok(expected_count)
+            );
+            assert_eq!(invoke_context.total_accounts(), 3);
+        }
+    }
+
+    #[cfg(feature = "dev-context-only-utils")]
+    #[test]
+    fn test_deterministic_rng() {
+        let sequence = |seed| {
+            let mut rng = DeterministicRng::new(seed);
+            (0..4).map(|_| rng.next_u64()).collect::<Vec<_>>()
+        };
+        assert_eq!(sequence(42), sequence(42));
+        assert_ne!(sequence(42), sequence(43));
+
+        let mut bytes = [0; 12];
+        DeterministicRng::new(42).fill_bytes(&mut bytes);
+        let mut rng = DeterministicRng::new(42);
+        let first = rng.next_u64().to_le_bytes();
+        let second = rng.next_u64().to_le_bytes();
+        assert_eq!(bytes[..8], first);
+        assert_eq!(bytes[8..], second[..4]);
+
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        assert_eq!(
+            invoke_context.deterministic_rng(),
+            &DeterministicRng::new(0)
+        );
+    }
+
+    #[test]
+    fn test_new_with_capacity() {
+        struct MockInvokeContextCallback {}
+        impl InvokeContextCallback for MockInvokeContextCallback {}
+
+        let compute_budget = SVMTransactionExecutionBudget::default();
+        let mut transaction_context = TransactionContext::new(
+            vec![(solana_pubkey::new_rand(), AccountSharedData::default())],
+            Rent::default(),
+            compute_budget.max_instruction_stack_depth,
+            compute_budget.max_instruction_trace_length,
+        );
+        let mut program_cache_for_tx_batch = ProgramCacheForTxBatch::default();
+        let feature_set = SVMFeatureSet::default();
+        let sysvar_cache = SysvarCache::default();
+        let environment_config = EnvironmentConfig::new(
+            Hash::default(),
+            0,
+            &MockInvokeContextCallback {},
+            &feature_set,
+            &sysvar_cache,
+        );
+        let invoke_context = InvokeContext::new_with_capacity(
+            &mut transaction_context,
+            &mut program_cache_for_tx_batch,
+            environment_config,
+            None,
+            compute_budget,
+            SVMTransactionExecutionCost::default(),
+            5,
+            64,
+        );
+        assert!(invoke_context.syscall_context.capacity() >= 5);
+        assert!(invoke_context.frame_entry_compute_meter.capacity() >= 5);
+        assert!(invoke_context.get_traces().capacity() >= 64);
+        assert!(invoke_context.get_traces().is_empty());
+        assert_eq!(
+            invoke_context.get_remaining_compute_units(),
+            compute_budget.compute_unit_limit
+        );
+    }
+
+    #[test]
+    fn test_peak_frame_consumption() {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        invoke_context.mock_set_remaining(1_000);
+        assert_eq!(invoke_context.peak_frame_consumption(), 0);
+
+        for (amount, expected_peak) in [(100, 100), (300, 300), (50, 300)] {
+            invoke_context
+                .transaction_context
+                .get_next_instruction_context()
+                .unwrap()
+                .configure(&[0], &[], &[]);
+            invoke_context.push().unwrap();
+            invoke_context.try_consume(amount).unwrap();
+            invoke_context.pop().unwrap();
+            assert_eq!(invoke_context.peak_frame_consumption(), expected_peak);
+        }
+
+        invoke_context.reset();
+        assert_eq!(invoke_context.peak_frame_consumption(), 0);
+    }
+
+    #[test]
+    fn test_peak_frame_consumption_nested() {
+        let transaction_accounts = vec![
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+        ];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        invoke_context.mock_set_remaining(1_000);
+        for (program_index, amount) in [(0, 10), (1, 300)] {
+            invoke_context
+                .transaction_context
+                .get_next_instruction_context()
+                .unwrap()
+                .configure(&[program_index], &[], &[]);
+            invoke_context.push().unwrap();
+            invoke_context.try_consume(amount).unwrap();
+        }
+        assert_eq!(invoke_context.pop_with_usage(), Ok(300));
+        invoke_context.try_consume(20).unwrap();
+        assert_eq!(invoke_context.pop_with_usage(), Ok(330));
+
+        // The CPI is the hot spot, even though its caller consumed more in total
+        assert_eq!(invoke_context.peak_frame_consumption(), 300);
+    }
+
+    #[test]
+    fn test_charge_heap_cost() {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let heap_cost = invoke_context.get_execution_cost().heap_cost;
+        for (heap_bytes, expected_pages) in [
+            (0, 0),
+            (MIN_HEAP_FRAME_BYTES, 0),
+            (MIN_HEAP_FRAME_BYTES.saturating_add(1), 1),
+            (MIN_HEAP_FRAME_BYTES.saturating_mul(2), 1),
+            (MAX_HEAP_FRAME_BYTES, 7),
+        ] {
+            invoke_context.mock_set_remaining(1_000);
+            invoke_context.charge_heap_cost(heap_bytes).unwrap();
+            assert_eq!(
+                invoke_context.get_remaining_compute_units(),
+                1_000u64.saturating_sub(heap_cost.saturating_mul(expected_pages))
+            );
+        }
+
+        invoke_context.mock_set_remaining(1_000);
+        assert_eq!(
+            invoke_context.charge_heap_cost(MAX_HEAP_FRAME_BYTES.saturating_add(1)),
+            Err(InstructionError::InvalidArgument)
+        );
+        assert_eq!(invoke_context.get_remaining_compute_units(), 1_000);
+    }
+
+    #[test]
+    fn test_charge_memory_op() {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let mem_op_base_cost = invoke_context.get_execution_cost().mem_op_base_cost;
+        let cpi_bytes_per_unit = invoke_context.get_execution_cost().cpi_bytes_per_unit;
+        let large = cpi_bytes_per_unit.saturating_mul(mem_op_base_cost.saturating_add(5));
+        for (bytes, expected_cost) in [
+            (0, mem_op_base_cost),
+            (1, mem_op_base_cost),
+            (cpi_bytes_per_unit, mem_op_base_cost),
+            (large, mem_op_base_cost.saturating_add(5)),
+        ] {
+            invoke_context.mock_set_remaining(1_000);
+            invoke_context.charge_memory_op(bytes).unwrap();
+            assert_eq!(
+                invoke_context.get_remaining_compute_units(),
+                1_000u64.saturating_sub(expected_cost)
+            );
+        }
+
+        invoke_context.mock_set_remaining(mem_op_base_cost.saturating_sub(1));
+        assert_eq!(
+            invoke_context.charge_memory_op(1),
+            Err(InstructionError::ComputationalBudgetExceeded)
+        );
+    }
+
+    #[test]
+    fn test_available_syscalls() {
+        let program_id = solana_pubkey::new_rand();
+        let transaction_accounts = vec![
+            (program_id, AccountSharedData::default()),
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+        ];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let mut program_cache_for_tx_batch = ProgramCacheForTxBatch::default();
+        program_cache_for_tx_batch.replenish(
+            program_id,
+            Arc::new(ProgramCacheEntry::new_builtin(0, 1, MockBuiltin::vm)),
+        );
+        invoke_context.program_cache_for_tx_batch = &mut program_cache_for_tx_batch;
+        assert_eq!(
+            invoke_context.available_syscalls(),
+            Err(InstructionError::CallDepth)
+        );
+
+        for (program_index, expected_result) in [
+            (0, Ok(Vec::new())),
+            (1, Err(InstructionError::UnsupportedProgramId)),
+        ] {
+            invoke_context
+                .transaction_context
+                .get_next_instruction_context()
+                .unwrap()
+                .configure(&[program_index], &[], &[]);
+            invoke_context.push().unwrap();
+            assert_eq!(invoke_context.available_syscalls(), expected_result);
+            invoke_context.pop().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_available_syscalls_of_filtered_vtable() {
+        let program_id = solana_pubkey::new_rand();
+        let transaction_accounts = vec![(program_id, AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let mut loader = BuiltinProgram::new_loader(Config::default());
+        for name in ["sol_memcpy_", "sol_log_"] {
+            loader.register_function(name, MockBuiltin::vm).unwrap();
+        }
+        // A program consisting of a single `exit` instruction
+        let executable = Executable::from_text_bytes(
+            &[0x95, 0, 0, 0, 0, 0, 0, 0],
+            Arc::new(loader),
+            SBPFVersion::V0,
+            FunctionRegistry::default(),
+        )
+        .unwrap();
+        let mut program_cache_for_tx_batch = ProgramCacheForTxBatch::default();
+        program_cache_for_tx_batch.replenish(
+            program_id,
+            Arc::new(ProgramCacheEntry {
+                program: ProgramCacheEntryType::Loaded(executable),
+                ..ProgramCacheEntry::default()
+            }),
+        );
+        invoke_context.program_cache_for_tx_batch = &mut program_cache_for_tx_batch;
+        invoke_context
+            .transaction_context
+            .get_next_instruction_context()
+            .unwrap()
+            .configure(&[0], &[], &[]);
+        invoke_context.push().unwrap();
+        assert_eq!(
+            invoke_context.available_syscalls(),
+            Ok(vec!["sol_log_", "sol_memcpy_"])
+        );
+    }
+
+    #[test]
+    fn test_consumption_timeline() {
+        let transaction_accounts = vec![
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+        ];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let push = |invoke_context: &mut InvokeContext, program_index| {
+            invoke_context
+                .transaction_context
+                .get_next_instruction_context()
+                .unwrap()
+                .configure(&[program_index], &[], &[]);
+            invoke_context.push().unwrap();
+        };
+        push(&mut invoke_context, 0);
+        invoke_context.pop().unwrap();
+        assert!(invoke_context.consumption_timeline().is_empty());
+
+        invoke_context.mock_set_remaining(1_000);
+        invoke_context.set_record_consumption_timeline(true);
+        push(&mut invoke_context, 0);
+        invoke_context.try_consume(10).unwrap();
+        for (program_index, amount) in [(1, 100), (2, 200)] {
+            push(&mut invoke_context, program_index);
+            invoke_context.try_consume(amount).unwrap();
+            invoke_context.pop().unwrap();
+        }
+        invoke_context.try_consume(5).unwrap();
+        invoke_context.pop().unwrap();
+        assert_eq!(
+            invoke_context.consumption_timeline(),
+            vec![(1, 0), (2, 10), (1, 110), (2, 110), (1, 310), (0, 315)]
+        );
+
+        invoke_context.set_record_consumption_timeline(false);
+        push(&mut invoke_context, 0);
+        invoke_context.pop().unwrap();
+        assert!(invoke_context.consumption_timeline().is_empty());
+    }
+
+    #[test]
+    fn test_pop_without_push() {
+        with_mock_invoke_context!(invoke_context, transaction_context, Vec::new());
+        let remaining = invoke_context.get_remaining_compute_units();
+        assert_eq!(invoke_context.pop(), Err(InstructionError::CallDepth));
+        assert_eq!(invoke_context.get_stack_height(), 0);
+        assert_eq!(invoke_context.get_remaining_compute_units(), remaining);
+    }
+
+    #[test]
+    fn test_instruction_data() {
+        let transaction_accounts = vec![
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+            (solana_pubkey::new_rand(), AccountSharedData::default()),
+        ];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        assert_eq!(
+            invoke_context.instruction_data(),
+            Err(InstructionError::CallDepth)
+        );
+        for (program_index, instruction_data) in [(0, &[1, 2, 3][..]), (1, &[4, 5][..])] {
+            invoke_context
+                .transaction_context
+                .get_next_instruction_context()
+                .unwrap()
+                .configure(&[program_index], &[], instruction_data);
+            invoke_context.push().unwrap();
+            assert_eq!(invoke_context.instruction_data(), Ok(instruction_data));
+        }
+        invoke_context.pop().unwrap();
+        assert_eq!(invoke_context.instruction_data(), Ok([1, 2, 3].as_slice()));
+    }
+
+    #[test]
+    fn test_failed_push_keeps_frames_aligned() {
+        let transaction_accounts = vec![(solana_pubkey::new_rand(), AccountSharedData::default())];
+        with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+        let configure = |invoke_context: &mut InvokeContext| {
+            invoke_context
+                .transaction_context
+                .get_next_instruction_context()
+                .unwrap()
+                .configure(&[0], &[], &[]);
+        };
+        configure(&mut invoke_context);
+        invoke_context.push().unwrap();
+        invoke_context.consume(10);
+
+        // Fill the instruction trace with nested frames until the next push fails
+        let result = loop {
+            configure(&mut invoke_context);
+            if let Err(err) = invoke_context.push() {
+                break err;
+            }
+            invoke_context.pop().unwrap();
+        };
+        assert_eq!(result, InstructionError::MaxInstructionTraceLengthExceeded);
+        assert_eq!(invoke_context.get_stack_height(), 1);
+        assert_eq!(invoke_context.syscall_context.len(), 1);
+        assert_eq!(invoke_context.frame_entry_compute_meter.len(), 1);
+
+        invoke_context.consume(5);
+        assert_eq!(invoke_context.pop_with_usage(), Ok(15));
+    }
 }
\ No newline at end of file